@@ -1,16 +1,104 @@
 use std::process::exit;
 
 use clap::Parser;
-use p2p_handshake::p2p::{config::HandshakeConfig, handshake};
+use futures::StreamExt;
+use p2p_handshake::p2p::{
+    config::{HandshakeConfig, OutputFormat},
+    handshake,
+    summary::Summary,
+    view::HandshakeResult,
+    P2PError,
+};
+use serde::Serialize;
 
 #[tokio::main]
 async fn main() {
     let config = HandshakeConfig::parse();
-    match handshake(config).await {
-        Ok(handshake_result) => handshake_result.iter().for_each(|hr| println!("{}", hr)),
+    let output = config.output;
+    let mut summary = Summary::new(&config.commands);
+    let mut results = match handshake(config) {
+        Ok(results) => Box::pin(results),
         Err(err) => {
-            println!("{}", err);
-            exit(1)
+            match output {
+                OutputFormat::Text => println!("{}", err),
+                OutputFormat::Json | OutputFormat::Ndjson => {
+                    println!("{}", to_json(&JsonError { error: &err }))
+                }
+            }
+            exit(exit_code(&err))
         }
+    };
+
+    // Results are printed as soon as they arrive, except for the JSON output
+    // which needs all of them for building a single document.
+    let mut json_results = Vec::new();
+    let mut exit_status = 0;
+    while let Some(hr) = results.next().await {
+        // The exit code reflects the first failed handshake, if any.
+        if let (0, Some(err)) = (exit_status, hr.error()) {
+            exit_status = exit_code(err);
+        }
+        summary.add(&hr);
+        match output {
+            OutputFormat::Text => println!("{}", hr),
+            OutputFormat::Ndjson => println!("{}", to_json(&hr)),
+            OutputFormat::Json => json_results.push(hr),
+        }
+    }
+    // The summary goes last, as it is built from all the results.
+    let summary = Some(&summary).filter(|summary| !summary.is_empty());
+    match output {
+        OutputFormat::Text => summary.iter().for_each(|summary| println!("{}", summary)),
+        OutputFormat::Ndjson => summary
+            .iter()
+            .for_each(|summary| println!("{}", to_json(&JsonSummary { summary }))),
+        OutputFormat::Json => println!(
+            "{}",
+            to_json(&JsonOutput {
+                results: &json_results,
+                summary,
+            })
+        ),
+    }
+    exit(exit_status)
+}
+
+/// The document printed with the `json` output format.
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    results: &'a [HandshakeResult],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<&'a Summary>,
+}
+
+/// The last line printed with the `ndjson` output format, when a summary was requested.
+#[derive(Serialize)]
+struct JsonSummary<'a> {
+    summary: &'a Summary,
+}
+
+/// The document printed with the machine readable formats when the program fails.
+#[derive(Serialize)]
+struct JsonError<'a> {
+    error: &'a P2PError,
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("results are always serializable")
+}
+
+fn exit_code(err: &P2PError) -> i32 {
+    match err {
+        P2PError::Internal { .. } => 1,
+        P2PError::ResolveFailed { .. } => 2,
+        P2PError::ConnectFailed { .. } => 3,
+        P2PError::Timeout(_) => 4,
+        P2PError::PeerClosed(_) => 5,
+        P2PError::WrongNetwork { .. } => 6,
+        P2PError::ProtocolViolation(_) => 7,
+        P2PError::Decode(_) => 8,
+        P2PError::Io(_) => 9,
+        P2PError::InvalidTarget { .. } => 10,
+        P2PError::AuthenticationFailed(_) => 11,
     }
 }
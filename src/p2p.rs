@@ -1,111 +1,392 @@
-use std::fmt;
+use std::{error::Error, fmt, io, net::SocketAddr, sync::Arc, time::Duration};
 
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt, Stream, StreamExt,
+};
 use tokio::{
     sync::{broadcast::error::RecvError, mpsc::error::SendError},
-    task::{JoinError, JoinHandle},
-    time::error::Elapsed,
+    task::JoinError,
+    time::{self, Instant, MissedTickBehavior},
 };
 
 use self::{
-    config::{Commands, HandshakeConfig},
+    config::{Commands, HandshakeConfig, Rate, RunConfig},
+    protocol::{Protocol, Timeouts},
+    targets::Target,
     view::{Event, EventChain, HandshakeResult},
 };
 
+mod bittorrent;
 mod btc;
 pub mod config;
+mod eth;
+mod libp2p;
+mod ln;
+pub mod protocol;
+pub mod proxy;
+pub mod summary;
+pub mod targets;
 pub mod view;
 
-pub async fn handshake(config: HandshakeConfig) -> Result<Vec<HandshakeResult>, P2PError> {
-    let join_handles: Vec<(String, JoinHandle<Result<EventChain, P2PError>>)> =
-        match &config.commands {
-            Commands::Btc {
-                nodes_addrs,
-                user_agent,
-            } => nodes_addrs
-                .iter()
-                .map(|node_addr| {
-                    let config = btc::Config {
-                        node_addr: node_addr.to_owned(),
-                        timeout: config.timeout.to_owned(),
-                        user_agent: user_agent.to_owned(),
-                    };
-                    let join = tokio::spawn(btc::handshake(config));
-                    (node_addr.to_owned(), join)
-                })
-                .collect(),
-        };
+/// Performs the handshakes with all the configured nodes concurrently, using the
+/// protocol of the configured command. See [handshake_with] for the details.
+///
+/// Fails if the targets cannot be loaded.
+pub fn handshake(config: HandshakeConfig) -> Result<impl Stream<Item = HandshakeResult>, P2PError> {
+    let handshakes = match &config.commands {
+        Commands::Bittorrent {
+            info_hash,
+            nodes_addrs,
+        } => {
+            let targets = targets::load(nodes_addrs, config.targets_file.as_deref())?;
+            handshake_with(&config.run, targets, |target| {
+                let mut bittorrent = bittorrent::Bittorrent::new(info_hash)?;
+                bittorrent.apply_options(&target.addr, &target.options)?;
+                Ok(bittorrent)
+            })
+            .boxed()
+        }
+        Commands::Btc {
+            nodes_addrs,
+            user_agent,
+            chain,
+            network,
+            version,
+            v2,
+            max_clock_skew,
+            pings,
+            ping_timeout,
+            ..
+        } => {
+            let targets = targets::load(nodes_addrs, config.targets_file.as_deref())?;
+            let options = btc::Options {
+                chain: *chain,
+                network: *network,
+                user_agent: user_agent.to_owned(),
+                version: version.to_owned(),
+                v2: *v2,
+                max_clock_skew: *max_clock_skew,
+                pings: *pings,
+                ping_timeout: *ping_timeout,
+            };
+            handshake_with(&config.run, targets, move |target| {
+                let mut btc = btc::Btc::new(&target.addr, options.clone())?;
+                btc.apply_options(&target.addr, &target.options)?;
+                Ok(btc)
+            })
+            .boxed()
+        }
+        Commands::Eth {
+            nodes_addrs,
+            client_id,
+        } => {
+            let targets = targets::load(nodes_addrs, config.targets_file.as_deref())?;
+            handshake_with(&config.run, targets, |target| {
+                let mut eth = eth::Eth::new(&target.addr, client_id.to_owned())?;
+                eth.apply_options(&target.addr, &target.options)?;
+                Ok(eth)
+            })
+            .boxed()
+        }
+        Commands::Libp2p {
+            nodes_addrs,
+            no_identify,
+        } => {
+            let targets = targets::load(nodes_addrs, config.targets_file.as_deref())?;
+            handshake_with(&config.run, targets, |target| {
+                let mut libp2p = libp2p::Libp2p::new(&target.addr, !no_identify)?;
+                libp2p.apply_options(&target.addr, &target.options)?;
+                Ok(libp2p)
+            })
+            .boxed()
+        }
+        Commands::Ln {
+            nodes_addrs,
+            network,
+        } => {
+            let targets = targets::load(nodes_addrs, config.targets_file.as_deref())?;
+            handshake_with(&config.run, targets, |target| {
+                let mut ln = ln::Ln::new(&target.addr, *network)?;
+                ln.apply_options(&target.addr, &target.options)?;
+                Ok(ln)
+            })
+            .boxed()
+        }
+    };
+    Ok(handshakes)
+}
+
+/// Performs the handshakes of any [Protocol] with the provided targets concurrently.
+/// The protocol of each target is built by `new_protocol`, which allows applying its
+/// options. Results are yielded as soon as each handshake finishes, so they are
+/// ordered by completion.
+///
+/// No more than `concurrency` handshakes are in flight at the same time, and new ones
+/// are started respecting the configured `rate`, whatever the protocol is. Handshakes
+/// still queued or in flight once the `deadline` is reached are timed out.
+pub fn handshake_with<P, F>(
+    config: &RunConfig,
+    targets: Vec<Target>,
+    mut new_protocol: F,
+) -> impl Stream<Item = HandshakeResult> + Send + 'static
+where
+    P: Protocol,
+    F: FnMut(&Target) -> Result<P, P2PError>,
+{
+    let deadline = config
+        .deadline
+        .map(|deadline| Instant::now() + Duration::from_millis(deadline));
+    let timeouts = Timeouts {
+        connect: Duration::from_millis(config.connect_timeout),
+        handshake: Duration::from_millis(config.handshake_timeout),
+        deadline,
+    };
+    let proxy = config.proxy.clone().map(Arc::new);
+    let handshakes: Vec<(String, BoxFuture<'static, HandshakeResult>)> = targets
+        .into_iter()
+        .map(|target| {
+            let handshake = match new_protocol(&target) {
+                Ok(protocol) => {
+                    protocol::run_through(target.addr.to_owned(), timeouts, proxy.clone(), protocol)
+                        .boxed()
+                }
+                Err(err) => failed(target.addr.to_owned(), err).boxed(),
+            };
+            (target.addr, handshake)
+        })
+        .collect();
 
-    let mut results = Vec::new();
-    for (addr, jh) in join_handles {
-        let res = jh.await?;
-        results.push(HandshakeResult::new(addr, res))
+    stream::iter(handshakes)
+        .zip(pacer(config.rate))
+        .map(move |((id, handshake), _)| async move {
+            if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                return failed(id, P2PError::Timeout(Phase::Queued)).await;
+            }
+            // Spawning each handshake allows them to make progress in parallel.
+            match tokio::spawn(handshake).await {
+                Ok(result) => result,
+                Err(err) => HandshakeResult::new(EventChain::new(id), Some(err.into())),
+            }
+        })
+        .buffer_unordered(config.concurrency.get())
+}
+
+async fn failed(id: String, err: P2PError) -> HandshakeResult {
+    HandshakeResult::new(EventChain::new(id), Some(err))
+}
+
+/// A stream that yields as often as the provided rate allows, or without
+/// any wait if there is no rate.
+fn pacer(rate: Option<Rate>) -> BoxStream<'static, ()> {
+    match rate {
+        Some(rate) => {
+            let mut interval = time::interval(rate.period());
+            // Do not burst for catching up after waiting for a free concurrency slot.
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            stream::unfold(interval, |mut interval| async move {
+                interval.tick().await;
+                Some(((), interval))
+            })
+            .boxed()
+        }
+        None => stream::repeat(()).boxed(),
     }
-    Ok(results)
 }
 
 #[derive(Debug)]
-pub struct P2PError {
-    message: String,
+pub enum P2PError {
+    /// The target is not valid, like having unknown options.
+    InvalidTarget { target: String, reason: String },
+    /// The node address is not valid or could not be resolved to any IP address.
+    ResolveFailed { target: String, source: io::Error },
+    /// The connection to the node could not be stablished.
+    ConnectFailed { addr: SocketAddr, source: io::Error },
+    /// The operation did not finish in time.
+    Timeout(Phase),
+    /// The peer closed the connection before the operation finished.
+    PeerClosed(Option<io::Error>),
+    /// The peer belongs to a different network than the requested one.
+    WrongNetwork { expected: String, received: String },
+    /// The peer sent something that is not allowed by the protocol.
+    ProtocolViolation(String),
+    /// The peer identity could not be authenticated, like when its node key is not
+    /// the expected one.
+    AuthenticationFailed(String),
+    /// A message from the peer could not be decoded.
+    Decode(Box<dyn Error + Send + Sync>),
+    /// Any other IO error on an already stablished connection.
+    Io(io::Error),
+    /// Errors of the program itself, like failing channels or tasks.
+    Internal {
+        message: String,
+        source: Option<Box<dyn Error + Send + Sync>>,
+    },
+}
+
+/// The phases a handshake goes through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for a free concurrency slot or the rate limit.
+    Queued,
+    /// Resolving the node address.
+    Resolve,
+    /// Stablishing the connection.
+    Connect,
+    /// Exchanging the handshake messages.
+    Handshake,
+}
+
+impl Phase {
+    /// A stable identifier of the phase, as used in the machine readable outputs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Queued => "queued",
+            Phase::Resolve => "resolve",
+            Phase::Connect => "connect",
+            Phase::Handshake => "handshake",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let phase = match self {
+            Phase::Queued => "queue wait",
+            Phase::Resolve => "address resolution",
+            Phase::Connect => "connection",
+            Phase::Handshake => "handshake",
+        };
+        write!(f, "{}", phase)
+    }
+}
+
+impl P2PError {
+    /// A stable identifier of the failure kind, as used in the machine readable outputs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            P2PError::InvalidTarget { .. } => "invalid_target",
+            P2PError::ResolveFailed { .. } => "resolve_failed",
+            P2PError::ConnectFailed { .. } => "connect_failed",
+            P2PError::Timeout(_) => "timeout",
+            P2PError::PeerClosed(_) => "peer_closed",
+            P2PError::WrongNetwork { .. } => "wrong_network",
+            P2PError::ProtocolViolation(_) => "protocol_violation",
+            P2PError::AuthenticationFailed(_) => "authentication_failed",
+            P2PError::Decode(_) => "decode",
+            P2PError::Io(_) => "io",
+            P2PError::Internal { .. } => "internal",
+        }
+    }
+
+    pub(crate) fn internal(
+        message: &str,
+        source: Option<Box<dyn Error + Send + Sync>>,
+    ) -> P2PError {
+        P2PError::Internal {
+            message: message.to_string(),
+            source,
+        }
+    }
 }
 
 impl fmt::Display for P2PError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "P2P error: {}", self.message)
+        write!(f, "P2P error: ")?;
+        match self {
+            P2PError::InvalidTarget { target, reason } => {
+                write!(f, "invalid target {}: {}", target, reason)
+            }
+            P2PError::ResolveFailed { target, source } => {
+                write!(f, "cannot resolve {}: {}", target, source)
+            }
+            P2PError::ConnectFailed { addr, source } => {
+                write!(f, "cannot connect to {}: {}", addr, source)
+            }
+            P2PError::Timeout(phase) => write!(f, "timed out during {}", phase),
+            P2PError::PeerClosed(None) => write!(f, "connection closed by peer"),
+            P2PError::PeerClosed(Some(source)) => {
+                write!(f, "connection closed by peer: {}", source)
+            }
+            P2PError::WrongNetwork { expected, received } => write!(
+                f,
+                "wrong network: expected {}, peer answered with {}",
+                expected, received
+            ),
+            P2PError::ProtocolViolation(reason) => write!(f, "protocol violation: {}", reason),
+            P2PError::AuthenticationFailed(reason) => {
+                write!(f, "authentication failed: {}", reason)
+            }
+            P2PError::Decode(source) => write!(f, "cannot decode message: {}", source),
+            P2PError::Io(source) => write!(f, "{}", source),
+            P2PError::Internal {
+                message,
+                source: None,
+            } => write!(f, "{}", message),
+            P2PError::Internal {
+                message,
+                source: Some(source),
+            } => write!(f, "{}: {}", message, source),
+        }
     }
 }
 
-impl From<SendError<Event>> for P2PError {
-    fn from(err: SendError<Event>) -> Self {
-        P2PError {
-            message: err.to_string(),
+impl Error for P2PError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            P2PError::ResolveFailed { source, .. }
+            | P2PError::ConnectFailed { source, .. }
+            | P2PError::PeerClosed(Some(source))
+            | P2PError::Io(source) => Some(source),
+            P2PError::Decode(source)
+            | P2PError::Internal {
+                source: Some(source),
+                ..
+            } => Some(source.as_ref()),
+            _ => None,
         }
     }
 }
 
 impl From<std::io::Error> for P2PError {
     fn from(err: std::io::Error) -> Self {
-        P2PError {
-            message: err.to_string(),
+        match err.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => P2PError::PeerClosed(Some(err)),
+            _ => P2PError::Io(err),
         }
     }
 }
 
+impl From<SendError<Event>> for P2PError {
+    fn from(_: SendError<Event>) -> Self {
+        P2PError::internal("event channel closed", None)
+    }
+}
+
 impl From<SendError<usize>> for P2PError {
     fn from(err: SendError<usize>) -> Self {
-        P2PError {
-            message: err.to_string(),
-        }
+        P2PError::internal("channel closed", Some(Box::new(err)))
     }
 }
 
 impl From<RecvError> for P2PError {
     fn from(err: RecvError) -> Self {
-        P2PError {
-            message: err.to_string(),
-        }
+        P2PError::internal("shutdown channel failed", Some(Box::new(err)))
     }
 }
 
 impl From<tokio::sync::broadcast::error::SendError<usize>> for P2PError {
     fn from(err: tokio::sync::broadcast::error::SendError<usize>) -> Self {
-        P2PError {
-            message: err.to_string(),
-        }
+        P2PError::internal("shutdown channel failed", Some(Box::new(err)))
     }
 }
 
 impl From<JoinError> for P2PError {
     fn from(err: JoinError) -> Self {
-        P2PError {
-            message: err.to_string(),
-        }
-    }
-}
-
-impl From<Elapsed> for P2PError {
-    fn from(err: Elapsed) -> Self {
-        P2PError {
-            message: err.to_string(),
-        }
+        P2PError::internal("task failed", Some(Box::new(err)))
     }
 }
@@ -0,0 +1,446 @@
+use std::collections::BTreeMap;
+
+use bytes::{Buf, BytesMut};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::p2p::{
+    protocol::{Protocol, Session},
+    view::{Event, EventDirection},
+    P2PError,
+};
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+const HANDSHAKE_LEN: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
+/// The Azureus style prefix of our peer ids, followed by random characters.
+const PEER_ID_PREFIX: &[u8] = b"-PH0100-";
+
+/// The client name announced in our extended handshake.
+const CLIENT_NAME: &str = concat!("p2p-handshake ", env!("CARGO_PKG_VERSION"));
+
+/// The reserved bits of the features we care about, as (byte, mask, name).
+const DHT: (usize, u8, &str) = (7, 0x01, "dht");
+const FAST: (usize, u8, &str) = (7, 0x04, "fast");
+const EXTENSION_PROTOCOL: (usize, u8, &str) = (5, 0x10, "extension");
+
+const EXTENDED_MESSAGE_ID: u8 = 20;
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// The longest message we accept before the extended handshake. Bitfields of huge
+/// torrents are the longest ones.
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// The BitTorrent peer wire handshake, followed by the BEP 10 extended handshake when
+/// the peer supports the extension protocol.
+pub struct Bittorrent {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    stage: Stage,
+}
+
+enum Stage {
+    /// Waiting for the handshake of the peer, once ours was sent.
+    Handshake,
+    /// Waiting for the extended handshake of the peer, once ours was sent.
+    Extended,
+    Complete,
+}
+
+/// The BEP 10 extended handshake dictionary, only with the fields we are interested in.
+/// The fields are kept in alphabetical order, as bencode requires for dictionary keys.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct ExtendedHandshake {
+    /// The extension messages supported by the peer, along their message ids.
+    #[serde(default)]
+    m: BTreeMap<String, i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata_size: Option<i64>,
+    /// The client name and version. Some clients send it in other encodings than UTF-8,
+    /// so it is kept as bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v: Option<ByteBuf>,
+}
+
+impl Bittorrent {
+    /// Prepares the handshake for the torrent of the provided hex encoded info hash.
+    /// A new peer id is used for every handshake.
+    pub fn new(info_hash: &str) -> Result<Bittorrent, P2PError> {
+        let mut peer_id = [0; 20];
+        peer_id[..PEER_ID_PREFIX.len()].copy_from_slice(PEER_ID_PREFIX);
+        for byte in &mut peer_id[PEER_ID_PREFIX.len()..] {
+            *byte = thread_rng().sample(Alphanumeric);
+        }
+        Ok(Bittorrent {
+            info_hash: parse_info_hash(info_hash)?,
+            peer_id,
+            stage: Stage::Handshake,
+        })
+    }
+
+    /// Overrides the configuration with the options of a single target, like
+    /// `info_hash=<hex info hash>`.
+    pub fn apply_options(
+        &mut self,
+        target: &str,
+        options: &[(String, String)],
+    ) -> Result<(), P2PError> {
+        for (key, val) in options {
+            let invalid_option = |reason: String| P2PError::InvalidTarget {
+                target: target.to_string(),
+                reason,
+            };
+            match key.as_str() {
+                "info_hash" => {
+                    self.info_hash = parse_info_hash(val).map_err(|_| {
+                        invalid_option(format!("the info hash {} is not valid", val))
+                    })?
+                }
+                _ => return Err(invalid_option(format!("unknown option {}", key))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates the handshake of the peer, sending our extended handshake when both
+    /// sides support the extension protocol.
+    fn handle_handshake(
+        &mut self,
+        handshake: &[u8],
+        session: &mut Session,
+    ) -> Result<(), P2PError> {
+        let (protocol_len, rest) = handshake.split_at(1);
+        let (protocol, rest) = rest.split_at(PROTOCOL.len());
+        if protocol_len[0] as usize != PROTOCOL.len() || protocol != PROTOCOL {
+            return Err(P2PError::ProtocolViolation(format!(
+                "unknown protocol {}",
+                String::from_utf8_lossy(protocol)
+            )));
+        }
+        let (reserved, rest) = rest.split_at(8);
+        let (info_hash, peer_id) = rest.split_at(20);
+
+        let mut event = Event::new("handshake".to_string(), EventDirection::IN);
+        event.set_pair("peer-id".to_string(), display_peer_id(peer_id));
+        event.set_pair("reserved".to_string(), hex::encode(reserved));
+        let features: Vec<&str> = [DHT, FAST, EXTENSION_PROTOCOL]
+            .iter()
+            .filter(|(byte, mask, _)| reserved[*byte] & mask != 0)
+            .map(|(_, _, name)| *name)
+            .collect();
+        let names: Vec<String> = features.iter().map(|name| name.to_string()).collect();
+        event.set_pair("features".to_string(), names);
+        session.publish(event)?;
+
+        if info_hash != self.info_hash {
+            return Err(P2PError::ProtocolViolation(format!(
+                "info hash {} echoed instead of {}",
+                hex::encode(info_hash),
+                hex::encode(self.info_hash)
+            )));
+        }
+        if !features.contains(&EXTENSION_PROTOCOL.2) {
+            self.stage = Stage::Complete;
+            return Ok(());
+        }
+
+        let extended_handshake = ExtendedHandshake {
+            v: Some(ByteBuf::from(CLIENT_NAME)),
+            ..Default::default()
+        };
+        let payload = serde_bencode::to_bytes(&extended_handshake)
+            .map_err(|err| P2PError::internal("cannot encode message", Some(Box::new(err))))?;
+        let event = Event::new("extended".to_string(), EventDirection::OUT);
+        session.send(event, extended_message(EXTENDED_HANDSHAKE_ID, &payload))?;
+        self.stage = Stage::Extended;
+        Ok(())
+    }
+
+    /// Handles a message received while waiting for the extended handshake. Peers
+    /// usually announce the pieces they have before, which are ignored.
+    fn handle_message(&mut self, message: &[u8], session: &mut Session) -> Result<(), P2PError> {
+        if let [EXTENDED_MESSAGE_ID, EXTENDED_HANDSHAKE_ID, payload @ ..] = message {
+            let extended_handshake: ExtendedHandshake = serde_bencode::from_bytes(payload)
+                .map_err(|err| P2PError::Decode(Box::new(err)))?;
+            let mut event = Event::new("extended".to_string(), EventDirection::IN);
+            if let Some(client) = extended_handshake.v {
+                event.set_pair(
+                    "client".to_string(),
+                    String::from_utf8_lossy(&client).into_owned(),
+                );
+            }
+            if let Some(metadata_size) = extended_handshake.metadata_size {
+                event.set_pair("metadata-size".to_string(), metadata_size);
+            }
+            let extensions: Vec<String> = extended_handshake.m.into_keys().collect();
+            event.set_pair("extensions".to_string(), extensions);
+            session.publish(event)?;
+            self.stage = Stage::Complete;
+        }
+        Ok(())
+    }
+}
+
+impl Protocol for Bittorrent {
+    fn default_port(&self) -> u16 {
+        6881
+    }
+
+    fn start(&mut self, session: &mut Session) -> Result<(), P2PError> {
+        let mut handshake = Vec::with_capacity(HANDSHAKE_LEN);
+        handshake.push(PROTOCOL.len() as u8);
+        handshake.extend_from_slice(PROTOCOL);
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_PROTOCOL.0] |= EXTENSION_PROTOCOL.1;
+        handshake.extend_from_slice(&reserved);
+        handshake.extend_from_slice(&self.info_hash);
+        handshake.extend_from_slice(&self.peer_id);
+        let event = Event::new("handshake".to_string(), EventDirection::OUT);
+        session.send(event, handshake)
+    }
+
+    fn on_data(&mut self, buffer: &mut BytesMut, session: &mut Session) -> Result<(), P2PError> {
+        loop {
+            match self.stage {
+                Stage::Handshake => {
+                    if buffer.len() < HANDSHAKE_LEN {
+                        return Ok(());
+                    }
+                    let handshake = buffer.split_to(HANDSHAKE_LEN);
+                    self.handle_handshake(&handshake, session)?;
+                }
+                Stage::Extended => {
+                    if buffer.len() < 4 {
+                        return Ok(());
+                    }
+                    let len = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+                    let len = len as usize;
+                    if len > MAX_MESSAGE_LEN {
+                        return Err(P2PError::Decode(
+                            format!("message of {} bytes is too long", len).into(),
+                        ));
+                    }
+                    if buffer.len() < 4 + len {
+                        return Ok(());
+                    }
+                    buffer.advance(4);
+                    let message = buffer.split_to(len);
+                    self.handle_message(&message, session)?;
+                }
+                Stage::Complete => return Ok(()),
+            }
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        matches!(self.stage, Stage::Complete)
+    }
+}
+
+fn parse_info_hash(info_hash: &str) -> Result<[u8; 20], P2PError> {
+    let mut parsed = [0; 20];
+    hex::decode_to_slice(info_hash, &mut parsed).map_err(|_| P2PError::InvalidTarget {
+        target: info_hash.to_string(),
+        reason: "the info hash must be 40 hex characters".to_string(),
+    })?;
+    Ok(parsed)
+}
+
+/// A length prefixed extended message.
+fn extended_message(extended_id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut message = ((payload.len() + 2) as u32).to_be_bytes().to_vec();
+    message.push(EXTENDED_MESSAGE_ID);
+    message.push(extended_id);
+    message.extend_from_slice(payload);
+    message
+}
+
+/// Peer ids are usually printable, like the Azureus style `-qB4630-` ones. Those that
+/// are not are shown in hex.
+fn display_peer_id(peer_id: &[u8]) -> String {
+    match peer_id.iter().all(|byte| byte.is_ascii_graphic()) {
+        true => String::from_utf8_lossy(peer_id).into_owned(),
+        false => hex::encode(peer_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::p2p::protocol::{
+        run,
+        tests::{peer, TIMEOUTS},
+    };
+
+    const INFO_HASH: &str = "dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c";
+
+    /// Starts a peer that answers the handshake with the provided bytes, returning its
+    /// address and the handshake it received.
+    async fn responder(answer: Vec<u8>) -> (String, tokio::task::JoinHandle<Vec<u8>>) {
+        let (addr, handle) = peer(|mut stream| async move {
+            let mut handshake = vec![0; HANDSHAKE_LEN];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&answer).await.unwrap();
+            // Wait for the peer to close the connection.
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+            handshake
+        })
+        .await;
+        (addr.to_string(), handle)
+    }
+
+    fn handshake(reserved: [u8; 8], info_hash: &str) -> Vec<u8> {
+        let mut handshake = vec![PROTOCOL.len() as u8];
+        handshake.extend_from_slice(PROTOCOL);
+        handshake.extend_from_slice(&reserved);
+        handshake.extend(hex::decode(info_hash).unwrap());
+        handshake.extend_from_slice(b"-qB4630-k8hj0wgej6ch");
+        handshake
+    }
+
+    fn messages(result: &crate::p2p::view::HandshakeResult) -> Vec<(&str, String)> {
+        result
+            .event_chain()
+            .events()
+            .iter()
+            .filter(|ev| !matches!(ev.direction(), EventDirection::INTERNAL))
+            .map(|ev| (ev.name(), ev.direction().to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn bittorrent_handshakes_with_extension_protocol_peers() {
+        let mut answer = handshake([0, 0, 0, 0, 0, 0x10, 0, 0x05], INFO_HASH);
+        // A bitfield, which comes before the extended handshake.
+        answer.extend_from_slice(&[0, 0, 0, 2, 5, 0xff]);
+        let payload =
+            b"d1:md11:ut_metadatai3e6:ut_pexi1ee13:metadata_sizei31235e1:v15:qBittorrent 4.6e";
+        answer.extend(extended_message(EXTENDED_HANDSHAKE_ID, payload));
+        let (addr, responder) = responder(answer).await;
+
+        let bittorrent = Bittorrent::new(INFO_HASH).unwrap();
+        let result = run(addr, TIMEOUTS, bittorrent).await;
+
+        assert!(result.event_chain().is_complete(), "{:?}", result.error());
+        let events = messages(&result);
+        let (out, r#in) = (
+            EventDirection::OUT.to_string(),
+            EventDirection::IN.to_string(),
+        );
+        assert_eq!(
+            vec![("handshake", out.clone()), ("handshake", r#in.clone())],
+            events[..2]
+        );
+        // Our extended handshake is sent while the peer one is already on its way.
+        assert_eq!(2, events[2..].len());
+        assert!(events[2..].contains(&("extended", out)));
+        assert!(events[2..].contains(&("extended", r#in)));
+
+        let data = |name: &str| {
+            result
+                .event_chain()
+                .events()
+                .iter()
+                .find(|ev| ev.name() == name && matches!(ev.direction(), EventDirection::IN))
+                .unwrap()
+                .data_pairs()
+                .to_vec()
+        };
+        assert_eq!(
+            vec![
+                ("peer-id".to_string(), "-qB4630-k8hj0wgej6ch".into()),
+                ("reserved".to_string(), "0000000000100005".into()),
+                (
+                    "features".to_string(),
+                    ["dht", "fast", "extension"]
+                        .map(String::from)
+                        .to_vec()
+                        .into()
+                ),
+            ],
+            data("handshake")
+        );
+        assert_eq!(
+            vec![
+                ("client".to_string(), "qBittorrent 4.6".into()),
+                ("metadata-size".to_string(), 31235i64.into()),
+                (
+                    "extensions".to_string(),
+                    ["ut_metadata", "ut_pex"].map(String::from).to_vec().into()
+                ),
+            ],
+            data("extended")
+        );
+
+        let our_handshake = responder.await.unwrap();
+        assert_eq!(
+            handshake([0, 0, 0, 0, 0, 0x10, 0, 0], INFO_HASH)[..48],
+            our_handshake[..48]
+        );
+        assert_eq!(PEER_ID_PREFIX, &our_handshake[48..56]);
+    }
+
+    #[tokio::test]
+    async fn bittorrent_accepts_client_names_not_in_utf8() {
+        let mut answer = handshake([0, 0, 0, 0, 0, 0x10, 0, 0], INFO_HASH);
+        let payload = b"d1:md11:ut_metadatai3ee1:v8:\xb5Torrente";
+        answer.extend(extended_message(EXTENDED_HANDSHAKE_ID, payload));
+        let (addr, _responder) = responder(answer).await;
+
+        let result = run(addr, TIMEOUTS, Bittorrent::new(INFO_HASH).unwrap()).await;
+
+        assert!(result.event_chain().is_complete(), "{:?}", result.error());
+        let client = result
+            .event_chain()
+            .events()
+            .iter()
+            .find(|ev| ev.name() == "extended" && matches!(ev.direction(), EventDirection::IN))
+            .and_then(|ev| ev.pair("client"))
+            .unwrap();
+        assert_eq!("\u{FFFD}Torrent", client.to_string());
+    }
+
+    #[tokio::test]
+    async fn bittorrent_completes_without_extension_protocol() {
+        let (addr, _) = responder(handshake([0; 8], INFO_HASH)).await;
+
+        let bittorrent = Bittorrent::new(INFO_HASH).unwrap();
+        let result = run(addr, TIMEOUTS, bittorrent).await;
+
+        assert!(result.event_chain().is_complete(), "{:?}", result.error());
+        assert_eq!(2, messages(&result).len());
+    }
+
+    #[tokio::test]
+    async fn bittorrent_rejects_other_info_hashes() {
+        let other = "0000000000000000000000000000000000000000";
+        let (addr, _) = responder(handshake([0; 8], other)).await;
+
+        let bittorrent = Bittorrent::new(INFO_HASH).unwrap();
+        let result = run(addr, TIMEOUTS, bittorrent).await;
+
+        assert_eq!(
+            format!(
+                "P2P error: protocol violation: info hash {} echoed instead of {}",
+                other, INFO_HASH
+            ),
+            result.error().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn bittorrent_rejects_invalid_info_hashes() {
+        for info_hash in [
+            "dd8255ecdc7ca55f",
+            "zz8255ecdc7ca55fb0bbf81323d87062db1f6d1c",
+        ] {
+            assert!(matches!(
+                Bittorrent::new(info_hash),
+                Err(P2PError::InvalidTarget { .. })
+            ));
+        }
+    }
+}
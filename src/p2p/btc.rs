@@ -1,267 +1,1568 @@
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    str::FromStr,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use bitcoin::{
-    consensus::{deserialize_partial, serialize},
+    consensus::{deserialize_partial, encode, serialize},
     network::{
         address,
-        constants::{self, ServiceFlags},
-        message::{self, NetworkMessage, RawNetworkMessage},
+        constants::{self, Network, ServiceFlags},
+        message::{NetworkMessage, RawNetworkMessage},
         message_network::VersionMessage,
     },
 };
 use bytes::{Buf, BytesMut};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{tcp::OwnedReadHalf, TcpStream},
-    select, signal,
-    sync::{
-        broadcast,
-        mpsc::{self, error::SendError, UnboundedSender},
-    },
-    try_join,
-};
+use clap::ValueEnum;
+use rand::{thread_rng, Rng};
 
 use crate::p2p::{
-    view::{Event, EventChain, EventDirection, EMOJI_WARNING},
+    config::{Chain, VersionConfig},
+    protocol::{Protocol, Session},
+    view::{Event, EventDirection},
     P2PError,
 };
 
-pub struct Config {
-    pub node_addr: String,
-    pub timeout: u64,
+use self::v2::{Cipher, Handshake, KEY_LEN};
+
+mod v2;
+
+/// The parameters of a network of one of the chains sharing the wire protocol of Bitcoin.
+#[derive(Debug, PartialEq, Eq)]
+struct ChainParams {
+    chain: Chain,
+    network: Network,
+    /// The name shown in the errors, which is the network one for Bitcoin.
+    name: &'static str,
+    magic: u32,
+    port: u16,
+    protocol_version: u32,
+}
+
+/// The known networks of every chain. Only the main and test networks of the Bitcoin
+/// forks are supported.
+const CHAINS: &[ChainParams] = &[
+    ChainParams {
+        chain: Chain::Bitcoin,
+        network: Network::Bitcoin,
+        name: "bitcoin",
+        magic: 0xd9b4bef9,
+        port: 8333,
+        protocol_version: constants::PROTOCOL_VERSION,
+    },
+    ChainParams {
+        chain: Chain::Bitcoin,
+        network: Network::Testnet,
+        name: "testnet",
+        magic: 0x0709110b,
+        port: 18333,
+        protocol_version: constants::PROTOCOL_VERSION,
+    },
+    ChainParams {
+        chain: Chain::Bitcoin,
+        network: Network::Signet,
+        name: "signet",
+        magic: 0x40cf030a,
+        port: 38333,
+        protocol_version: constants::PROTOCOL_VERSION,
+    },
+    ChainParams {
+        chain: Chain::Bitcoin,
+        network: Network::Regtest,
+        name: "regtest",
+        magic: 0xdab5bffa,
+        port: 18444,
+        protocol_version: constants::PROTOCOL_VERSION,
+    },
+    ChainParams {
+        chain: Chain::Litecoin,
+        network: Network::Bitcoin,
+        name: "litecoin",
+        magic: 0xdbb6c0fb,
+        port: 9333,
+        protocol_version: 70016,
+    },
+    ChainParams {
+        chain: Chain::Litecoin,
+        network: Network::Testnet,
+        name: "litecoin testnet",
+        magic: 0xf1c8d2fd,
+        port: 19335,
+        protocol_version: 70016,
+    },
+    ChainParams {
+        chain: Chain::Dogecoin,
+        network: Network::Bitcoin,
+        name: "dogecoin",
+        magic: 0xc0c0c0c0,
+        port: 22556,
+        protocol_version: 70015,
+    },
+    ChainParams {
+        chain: Chain::Dogecoin,
+        network: Network::Testnet,
+        name: "dogecoin testnet",
+        magic: 0xdcb7c1fc,
+        port: 44556,
+        protocol_version: 70015,
+    },
+    ChainParams {
+        chain: Chain::Bch,
+        network: Network::Bitcoin,
+        name: "bch",
+        magic: 0xe8f3e1e3,
+        port: 8333,
+        protocol_version: 70016,
+    },
+    ChainParams {
+        chain: Chain::Bch,
+        network: Network::Testnet,
+        name: "bch testnet",
+        magic: 0xf4f3e5f4,
+        port: 18333,
+        protocol_version: 70016,
+    },
+    ChainParams {
+        chain: Chain::Dash,
+        network: Network::Bitcoin,
+        name: "dash",
+        magic: 0xbd6b0cbf,
+        port: 9999,
+        protocol_version: 70230,
+    },
+    ChainParams {
+        chain: Chain::Dash,
+        network: Network::Testnet,
+        name: "dash testnet",
+        magic: 0xffcae2ce,
+        port: 19999,
+        protocol_version: 70230,
+    },
+];
+
+/// The known service flags, named like Bitcoin Core does.
+const SERVICE_FLAGS: &[(&str, u64)] = &[
+    ("network", 1 << 0),
+    ("getutxo", 1 << 1),
+    ("bloom", 1 << 2),
+    ("witness", 1 << 3),
+    ("compact_filters", 1 << 6),
+    ("network_limited", 1 << 10),
+    ("p2p_v2", 1 << 11),
+];
+
+/// Parses service flags given by name, like `network,witness`, or by value, like `1033`
+/// or `0x409`. No services are given with `none`.
+pub(crate) fn parse_services(s: &str) -> Result<ServiceFlags, String> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16)
+            .map(ServiceFlags::from)
+            .map_err(|_| format!("invalid services value {}", s));
+    }
+    if let Ok(value) = s.parse::<u64>() {
+        return Ok(ServiceFlags::from(value));
+    }
+    let mut services = ServiceFlags::NONE;
+    for name in s.split(',').map(str::trim) {
+        if name == "none" {
+            continue;
+        }
+        let (_, flag) = SERVICE_FLAGS
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let known: Vec<&str> = SERVICE_FLAGS.iter().map(|(known, _)| *known).collect();
+                format!("unknown service {}, use {} or none", name, known.join(", "))
+            })?;
+        services |= ServiceFlags::from(*flag);
+    }
+    Ok(services)
+}
+
+/// The names of the service flags, as shown by Bitcoin Core. Unknown flags are named
+/// by their bit, like `UNKNOWN[2^12]`.
+fn service_names(services: ServiceFlags) -> Vec<String> {
+    let services = u64::from(services);
+    if services == 0 {
+        return vec!["NONE".to_string()];
+    }
+    (0..64)
+        .map(|bit| 1 << bit)
+        .filter(|flag| services & flag != 0)
+        .map(
+            |flag| match SERVICE_FLAGS.iter().find(|(_, known)| *known == flag) {
+                Some((name, _)) => name.to_uppercase(),
+                None => format!("UNKNOWN[2^{}]", flag.trailing_zeros()),
+            },
+        )
+        .collect()
+}
+
+/// The socket address of a version message address. Tor v2 addresses, which are
+/// mapped into IPv6 ones, are shown as such.
+fn socket_addr(addr: &address::Address) -> SocketAddr {
+    addr.socket_addr()
+        .unwrap_or_else(|_| SocketAddr::new(Ipv6Addr::from(addr.address).into(), addr.port))
+}
+
+fn chain_params(chain: Chain, network: Network) -> Option<&'static ChainParams> {
+    CHAINS
+        .iter()
+        .find(|params| params.chain == chain && params.network == network)
+}
+
+/// The options of the `btc` command. Targets can override them one by one.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub chain: Chain,
+    pub network: Network,
     pub user_agent: String,
+    pub version: VersionConfig,
+    /// Whether the BIP324 v2 transport is tried first.
+    pub v2: bool,
+    /// The clock offset in seconds over which peers are flagged as skewed.
+    pub max_clock_skew: u64,
+    /// The ping messages sent once the handshake completes.
+    pub pings: u32,
+    /// The time in milliseconds the pings can take once the handshake completes.
+    pub ping_timeout: u64,
+}
+
+/// The Bitcoin network handshake, also used by the chains forked from it.
+pub struct Btc {
+    options: Options,
+    params: &'static ChainParams,
+    /// When our version was sent, for measuring the round trip of the peer one.
+    version_sent_at: Option<Instant>,
+    /// The number of pings sent so far.
+    pings_sent: u32,
+    /// The nonce of the ping waiting for its pong, along when it was sent.
+    ping_sent: Option<(u64, Instant)>,
+    /// The round trip times of the pings answered so far.
+    ping_rtts: Vec<Duration>,
+    transport: Transport,
+    state: HandshakeState,
 }
 
-const EXPECTED_HANDSHAKE_MESSAGES: usize = 4;
+/// The transport the messages are exchanged with.
+enum Transport {
+    /// The plain text transport, in which every message starts with the network magic.
+    V1,
+    /// The BIP324 transport, waiting for the key of the peer.
+    V2Key(Box<Handshake>),
+    /// The BIP324 transport, waiting for the garbage and version packet of the peer.
+    V2Version {
+        cipher: Box<Cipher>,
+        garbage_len: Option<usize>,
+        decoys: usize,
+    },
+    /// The BIP324 transport, once both version packets were exchanged.
+    V2(Box<Cipher>),
+}
 
-pub async fn handshake(config: Config) -> Result<EventChain, P2PError> {
-    // Setup shutdown broadcast channels
-    let (shutdown_tx, _) = broadcast::channel::<usize>(1);
+impl Btc {
+    /// Fails if the network is not supported by the chain.
+    pub fn new(target: &str, options: Options) -> Result<Btc, P2PError> {
+        Ok(Btc {
+            params: supported_params(target, options.chain, options.network)?,
+            options,
+            version_sent_at: None,
+            pings_sent: 0,
+            ping_sent: None,
+            ping_rtts: Vec::new(),
+            transport: Transport::V1,
+            state: HandshakeState::default(),
+        })
+    }
 
-    // Spawn the event chain task.
-    let (ev_tx, mut ev_rx) = mpsc::unbounded_channel();
-    let mut ev_shutdown_rx = shutdown_tx.subscribe();
-    let ev_shutdown_tx = shutdown_tx.clone();
-    let event_chain_id = config.node_addr.clone();
-    let event_chain_handle = tokio::spawn(async move {
-        let mut event_chain = EventChain::new(event_chain_id);
-        loop {
-            select! {
-                Some(ev) = ev_rx.recv() => {
-                    event_chain.add(ev);
+    /// Overrides the configuration with the options of a single target, like
+    /// `chain=litecoin`, `network=testnet`, `user_agent=/Satoshi:24.0.1/` or `v2=true`.
+    /// The version message fields are set with `protocol_version`, `services`,
+    /// `start_height`, `relay`, `sender` and `nonce`, the skew threshold with
+    /// `max_clock_skew` and the pings sent after the handshake with `pings` and
+    /// `ping_timeout`.
+    pub fn apply_options(
+        &mut self,
+        target: &str,
+        options: &[(String, String)],
+    ) -> Result<(), P2PError> {
+        for (key, val) in options {
+            let invalid_option = |reason: String| P2PError::InvalidTarget {
+                target: target.to_string(),
+                reason,
+            };
+            match key.as_str() {
+                "chain" => {
+                    self.options.chain = Chain::from_str(val, true)
+                        .map_err(|_| invalid_option(format!("unknown chain {}", val)))?
                 }
-                recv_res = ev_shutdown_rx.recv() => {
-                    return match recv_res {
-                        Ok(_) => Ok(event_chain),
-                        Err(err) => Err(P2PError::from(err)),
-                    }
+                "network" => {
+                    self.options.network = val
+                        .parse()
+                        .map_err(|_| invalid_option(format!("unknown network {}", val)))?
                 }
-            }
-            if event_chain.len() == EXPECTED_HANDSHAKE_MESSAGES {
-                event_chain.mark_as_complete();
-                ev_shutdown_tx.send(1)?;
-            }
-        }
-    });
-
-    // Stablish TCP connection with timeout.
-    let stream = tokio::time::timeout(
-        Duration::from_millis(config.timeout),
-        TcpStream::connect(&config.node_addr),
-    )
-    .await??;
-
-    let (rx_stream, mut tx_stream) = stream.into_split();
-
-    // Spawn the message writer task. This will take care of serialize all messages write to the socket.
-    let (msg_tx, mut msg_rx) = mpsc::unbounded_channel::<RawNetworkMessage>();
-    let msg_writer_ev_tx = ev_tx.clone();
-    let mut msg_writer_shutdown_rx = shutdown_tx.subscribe();
-    let msg_writer_handle = tokio::spawn(async move {
-        loop {
-            select! {
-                Some(msg) = msg_rx.recv() => {
-                    let msg_type = msg.cmd().to_string();
-                    let data = serialize(&msg);
-                    tx_stream.write_all(data.as_slice()).await?;
-                    msg_writer_ev_tx.send(Event::new(msg_type, EventDirection::OUT))?;
+                "user_agent" => self.options.user_agent = val.to_owned(),
+                "protocol_version" => {
+                    self.options.version.protocol_version =
+                        Some(val.parse().map_err(|_| {
+                            invalid_option(format!("invalid protocol version {}", val))
+                        })?)
                 }
-                result = msg_writer_shutdown_rx.recv() => {
-                    tx_stream.shutdown().await?;
-                    return match result {
-                        Ok(_) => Ok(()),
-                        Err(err) => Err(P2PError::from(err)),
-                    }
+                "services" => {
+                    self.options.version.services = parse_services(val).map_err(invalid_option)?
+                }
+                "start_height" => {
+                    self.options.version.start_height = val
+                        .parse()
+                        .map_err(|_| invalid_option(format!("invalid start height {}", val)))?
+                }
+                "relay" => {
+                    self.options.version.relay = val
+                        .parse()
+                        .map_err(|_| invalid_option(format!("invalid relay value {}", val)))?
                 }
+                "sender" => {
+                    self.options.version.sender =
+                        Some(val.parse().map_err(|_| {
+                            invalid_option(format!("invalid sender address {}", val))
+                        })?)
+                }
+                "nonce" => {
+                    self.options.version.nonce = Some(
+                        val.parse()
+                            .map_err(|_| invalid_option(format!("invalid nonce {}", val)))?,
+                    )
+                }
+                "max_clock_skew" => {
+                    self.options.max_clock_skew = val
+                        .parse()
+                        .map_err(|_| invalid_option(format!("invalid max clock skew {}", val)))?
+                }
+                "pings" => {
+                    self.options.pings = val
+                        .parse()
+                        .map_err(|_| invalid_option(format!("invalid pings {}", val)))?
+                }
+                "ping_timeout" => {
+                    self.options.ping_timeout = val
+                        .parse()
+                        .map_err(|_| invalid_option(format!("invalid ping timeout {}", val)))?
+                }
+                "v2" => {
+                    self.options.v2 = val
+                        .parse()
+                        .map_err(|_| invalid_option(format!("invalid v2 value {}", val)))?
+                }
+                _ => return Err(invalid_option(format!("unknown option {}", key))),
             }
         }
-    });
-
-    // Spawn the message reader task
-    let mut msg_reader_shutdown_rx = shutdown_tx.subscribe();
-    let msg_reader_msg_tx = msg_tx.clone();
-    let msg_reader_handle = tokio::spawn(async move {
-        // A complete handshake is about 342 bytes. We allocate much more so we don't need
-        // to do more allocations.
-        let mut msg_reader = MessageReader::new(rx_stream, 1024);
-        let mut handles = Vec::new();
-        loop {
-            select! {
-                message_res = msg_reader.read_message() => {
-                    match message_res {
-                        Ok(opt_res) => {
-                            if let Some(msg) = opt_res {
-                                let handle = tokio::spawn(handle_message(msg, msg_reader_msg_tx.clone(), ev_tx.clone()));
-                                handles.push(handle);
-                            }
-                         },
-                        Err(err) => return Err(err),
-                    }
-                },
-                result = msg_reader_shutdown_rx.recv() => {
-                   return match result {
-                     Ok(_) => {
-                       // Ensure all message handles succeeded before ending.
-                       futures::future::try_join_all(handles).await?;
-                       Ok(())
-                     },
-                     Err(err) => Err(P2PError::from(err)),
-                    }
-                }
+        self.params = supported_params(target, self.options.chain, self.options.network)?;
+        Ok(())
+    }
+
+    /// Records the message in the handshake state and queues it for sending.
+    fn send(&mut self, message: NetworkMessage, session: &mut Session) -> Result<(), P2PError> {
+        self.state.on_sent(&message);
+        if let NetworkMessage::Version(_) = message {
+            self.version_sent_at = Some(Instant::now());
+        }
+        let message = RawNetworkMessage {
+            magic: self.params.magic,
+            payload: message,
+        };
+        let mut event = Event::new(message.cmd().to_string(), EventDirection::OUT);
+        if let NetworkMessage::Ping(nonce) | NetworkMessage::Pong(nonce) = message.payload {
+            event.set_pair("nonce".to_string(), nonce);
+        }
+        let bytes = match &mut self.transport {
+            Transport::V2Version { cipher, .. } | Transport::V2(cipher) => {
+                cipher.encrypt(&v2::encode_message(&message))
             }
+            Transport::V1 | Transport::V2Key(_) => serialize(&message),
+        };
+        session.send(event, bytes)
+    }
+
+    /// Derives the v2 ciphers once the key of the peer is received, then sends our
+    /// version packet followed by our version message.
+    fn handle_key(
+        &mut self,
+        handshake: Handshake,
+        key: [u8; KEY_LEN],
+        session: &mut Session,
+    ) -> Result<(), P2PError> {
+        // The key is indistinguishable from random bytes, but peers only speaking v1 could
+        // still answer with a plain text message.
+        if key[..4] == self.params.magic.to_le_bytes() {
+            return Err(P2PError::ProtocolViolation(
+                "v1 message received instead of the v2 key".to_string(),
+            ));
         }
-    });
+        session.publish(Event::new("v2-key".to_string(), EventDirection::IN))?;
+        let (cipher, packets) = handshake.complete(key, &[]);
+        let event = Event::new("v2-version".to_string(), EventDirection::OUT);
+        session.send(event, packets)?;
+        self.transport = Transport::V2Version {
+            cipher: Box::new(cipher),
+            garbage_len: None,
+            decoys: 0,
+        };
+        let version = version_message(
+            self.params,
+            session.peer_addr(),
+            self.options.user_agent.clone(),
+            &self.options.version,
+        );
+        self.send(version.payload, session)
+    }
 
-    // Start the handshake by sending the first VERSION message
-    let version_message = version_message(config.node_addr, config.user_agent);
-    msg_tx.send(version_message)?;
+    /// Publishes the offset of the peer clock to ours, flagging the peer when it is
+    /// over the configured threshold.
+    fn publish_clock(&self, peer_timestamp: i64, session: &mut Session) -> Result<(), P2PError> {
+        let rtt = self
+            .version_sent_at
+            .map(|sent_at| sent_at.elapsed())
+            .unwrap_or_default();
+        let offset = clock_offset(peer_timestamp, SystemTime::now(), rtt);
+        let mut event = Event::new("clock".to_string(), EventDirection::INTERNAL);
+        event.set_pair("offset-s".to_string(), offset);
+        event.set_pair("rtt-ms".to_string(), rtt.as_millis() as u64);
+        event.set_pair(
+            "skewed".to_string(),
+            offset.unsigned_abs() > self.options.max_clock_skew,
+        );
+        session.publish(event)
+    }
 
-    // Wait for external shutdown signals ctr+c ...
-    let mut ext_shutdown_shutdown_rx = shutdown_tx.subscribe();
-    select! {
-        _ = tokio::time::sleep(Duration::from_millis(config.timeout)) => {
-            shutdown_tx.send(1)?;
+    /// Sends the next ping once the handshake is complete, if the previous one was
+    /// answered and there are pings left.
+    fn ping(&mut self, session: &mut Session) -> Result<(), P2PError> {
+        if !self.state.is_complete()
+            || self.ping_sent.is_some()
+            || self.pings_sent >= self.options.pings
+        {
+            return Ok(());
         }
-        val = signal::ctrl_c() => {
-            if val.is_ok(){
-                shutdown_tx.send(1)?;
+        let nonce = thread_rng().gen();
+        self.send(NetworkMessage::Ping(nonce), session)?;
+        self.pings_sent += 1;
+        self.ping_sent = Some((nonce, Instant::now()));
+        Ok(())
+    }
+
+    /// Matches the pong with the ping waiting for it. Pongs not matching it are
+    /// flagged, as they do not tell the round trip.
+    fn handle_pong(&mut self, nonce: u64, session: &mut Session) -> Result<(), P2PError> {
+        let mut event = Event::new("pong".to_string(), EventDirection::IN);
+        event.set_pair("nonce".to_string(), nonce);
+        match self.ping_sent {
+            Some((ping_nonce, sent_at)) if ping_nonce == nonce => {
+                let rtt = sent_at.elapsed();
+                event.set_pair("matched".to_string(), true);
+                event.set_pair("rtt-us".to_string(), rtt.as_micros() as u64);
+                self.ping_sent = None;
+                self.ping_rtts.push(rtt);
             }
+            _ => event.set_pair("matched".to_string(), false),
         }
-        // Break this select! once an internal shutdown is invoked from any of the subs systems.
-        _val = ext_shutdown_shutdown_rx.recv()=>{}
+        session.publish(event)
     }
 
-    let (event_chain_res, message_writer_res, msg_reader_res) =
-        try_join!(event_chain_handle, msg_writer_handle, msg_reader_handle)?;
-    // Check no errors happened in message reader and writer.
-    message_writer_res?;
-    msg_reader_res?;
-    // Finally, check the event chain was successful and return it.
-    event_chain_res
+    /// Validates the received message against the handshake state, answering it if needed.
+    /// Offending messages are published before failing, so they are part of the timeline.
+    fn handle_message(
+        &mut self,
+        message: RawNetworkMessage,
+        session: &mut Session,
+    ) -> Result<(), P2PError> {
+        let msg_type = message.cmd().to_string();
+        let transition = self.state.on_received(&message.payload);
+        let mut pong = None;
+        match message.payload {
+            NetworkMessage::Verack => {
+                session.publish(Event::new(msg_type, EventDirection::IN))?;
+            }
+            NetworkMessage::Version(v) => {
+                let timestamp = v.timestamp;
+                let mut event = Event::new(msg_type, EventDirection::IN);
+                event.set_pair("vers".to_string(), v.version);
+                event.set_pair("user-agent".to_string(), v.user_agent);
+                event.set_pair("services".to_string(), service_names(v.services));
+                event.set_pair("timestamp".to_string(), v.timestamp);
+                event.set_pair("receiver".to_string(), socket_addr(&v.receiver).to_string());
+                event.set_pair("sender".to_string(), socket_addr(&v.sender).to_string());
+                event.set_pair("nonce".to_string(), v.nonce);
+                event.set_pair("start-height".to_string(), v.start_height);
+                event.set_pair("relay".to_string(), v.relay);
+                let transport = match self.transport {
+                    Transport::V1 => "v1",
+                    _ => "v2",
+                };
+                event.set_pair("transport".to_string(), transport.to_string());
+                session.publish(event)?;
+                self.publish_clock(timestamp, session)?;
+            }
+            NetworkMessage::Ping(nonce) if transition.is_ok() => {
+                let mut event = Event::new(msg_type, EventDirection::IN);
+                event.set_pair("nonce".to_string(), nonce);
+                session.publish(event)?;
+                pong = Some(NetworkMessage::Pong(nonce));
+            }
+            NetworkMessage::Pong(nonce) if transition.is_ok() => {
+                self.handle_pong(nonce, session)?
+            }
+            // Once the handshake is complete, peers start announcing their preferences
+            // and inventory, which we are not interested in.
+            _ if transition.is_ok() && self.state.is_complete() => {}
+            // The rest, like the wtxidrelay and sendaddrv2 announcements sent before the
+            // verack, are just part of the timeline.
+            _ => session.publish(Event::new(msg_type, EventDirection::IN))?,
+        }
+        if let Some(answer) = transition? {
+            self.send(answer, session)?;
+        }
+        if let Some(pong) = pong {
+            self.send(pong, session)?;
+        }
+        self.ping(session)
+    }
 }
 
-async fn handle_message(
-    message: RawNetworkMessage,
-    msg_writer: UnboundedSender<RawNetworkMessage>,
-    event_publisher: UnboundedSender<Event>,
-) -> Result<(), P2PError> {
-    let msg_type = message.cmd().to_string();
-    match message.payload {
-        message::NetworkMessage::Verack => {
-            let event = Event::new(msg_type, EventDirection::IN);
-            event_publisher.send(event)?;
-            Ok(())
+impl Protocol for Btc {
+    fn default_port(&self) -> u16 {
+        self.params.port
+    }
+
+    fn start(&mut self, session: &mut Session) -> Result<(), P2PError> {
+        if self.options.v2 {
+            let handshake = Handshake::new(self.params.magic);
+            let event = Event::new("v2-key".to_string(), EventDirection::OUT);
+            session.send(event, handshake.key())?;
+            self.transport = Transport::V2Key(Box::new(handshake));
+            return Ok(());
         }
-        message::NetworkMessage::Version(v) => {
-            let mut event = Event::new(msg_type, EventDirection::IN);
-            event.set_pair("vers".to_string(), v.version.to_string());
-            event.set_pair("user-agent".to_string(), v.user_agent);
-            event_publisher.send(event)?;
-            msg_writer.send(verack_message())?;
-            Ok(())
+        let version = version_message(
+            self.params,
+            session.peer_addr(),
+            self.options.user_agent.clone(),
+            &self.options.version,
+        );
+        self.send(version.payload, session)
+    }
+
+    fn on_data(&mut self, buffer: &mut BytesMut, session: &mut Session) -> Result<(), P2PError> {
+        while !self.is_complete() || !self.is_followed_up() {
+            match &mut self.transport {
+                Transport::V1 => {
+                    // Every message starts with the network magic bytes, so we can reject
+                    // peers from other networks before waiting for a complete message.
+                    if buffer.len() >= 4 {
+                        let magic =
+                            u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+                        check_magic(self.params, magic)?;
+                    }
+
+                    match deserialize_partial::<RawNetworkMessage>(buffer) {
+                        Ok((message, count)) => {
+                            buffer.advance(count);
+                            self.handle_message(message, session)?;
+                        }
+                        // Not enough data for a complete message yet, wait for more.
+                        Err(encode::Error::Io(err))
+                            if err.kind() == io::ErrorKind::UnexpectedEof =>
+                        {
+                            break
+                        }
+                        Err(err) => return Err(P2PError::Decode(Box::new(err))),
+                    }
+                }
+                Transport::V2Key(_) => {
+                    if buffer.len() < KEY_LEN {
+                        break;
+                    }
+                    let key = buffer.split_to(KEY_LEN)[..]
+                        .try_into()
+                        .expect("64 bytes key");
+                    if let Transport::V2Key(handshake) =
+                        std::mem::replace(&mut self.transport, Transport::V1)
+                    {
+                        self.handle_key(*handshake, key, session)?;
+                    }
+                }
+                Transport::V2Version {
+                    cipher,
+                    garbage_len,
+                    decoys,
+                } => {
+                    if garbage_len.is_none() {
+                        match cipher.read_garbage(buffer)? {
+                            Some(len) => *garbage_len = Some(len),
+                            None => break,
+                        }
+                    }
+                    match cipher.decrypt(buffer)? {
+                        None => break,
+                        Some(packet) if packet.decoy => *decoys += 1,
+                        Some(_) => {
+                            let mut event =
+                                Event::new("v2-version".to_string(), EventDirection::IN);
+                            event.set_pair(
+                                "garbage".to_string(),
+                                garbage_len.unwrap_or_default() as u64,
+                            );
+                            event.set_pair("decoys".to_string(), *decoys as u64);
+                            // Bitcoin Core shows it among the peer info, so both sides can be matched.
+                            event.set_pair(
+                                "session-id".to_string(),
+                                hex::encode(cipher.session_id()),
+                            );
+                            session.publish(event)?;
+                            if let Transport::V2Version { cipher, .. } =
+                                std::mem::replace(&mut self.transport, Transport::V1)
+                            {
+                                self.transport = Transport::V2(cipher);
+                            }
+                        }
+                    }
+                }
+                Transport::V2(cipher) => match cipher.decrypt(buffer)? {
+                    None => break,
+                    Some(packet) if packet.decoy => {}
+                    Some(packet) => {
+                        let message = v2::decode_message(self.params.magic, &packet.contents)?;
+                        self.handle_message(message, session)?;
+                    }
+                },
+            }
         }
-        _ => {
-            println!(
-                "{}  received message type not part of handshake: {}",
-                EMOJI_WARNING, msg_type
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.state.is_complete()
+    }
+
+    fn follow_up(&self) -> Option<Duration> {
+        (self.options.pings > 0).then(|| Duration::from_millis(self.options.ping_timeout))
+    }
+
+    fn is_followed_up(&self) -> bool {
+        self.ping_rtts.len() >= self.options.pings as usize
+    }
+
+    /// Publishes the latency of the peer, along the pings that were not answered in time.
+    fn end_follow_up(&mut self, session: &mut Session) -> Result<(), P2PError> {
+        let mut event = Event::new("latency".to_string(), EventDirection::INTERNAL);
+        event.set_pair("pings-sent".to_string(), self.pings_sent);
+        event.set_pair("pongs-received".to_string(), self.ping_rtts.len() as u64);
+        if let Some(latency) = Latency::from_rtts(&self.ping_rtts) {
+            event.set_pair("min-us".to_string(), latency.min.as_micros() as u64);
+            event.set_pair("avg-us".to_string(), latency.avg.as_micros() as u64);
+            event.set_pair("max-us".to_string(), latency.max.as_micros() as u64);
+            event.set_pair("jitter-us".to_string(), latency.jitter.as_micros() as u64);
+        }
+        session.publish(event)
+    }
+
+    fn retry(&mut self, err: &P2PError) -> bool {
+        // Peers not supporting the v2 transport close the connection once our key arrives,
+        // as it is not a valid v1 message. So we fall back to v1 on failures happening
+        // before the version packets were exchanged.
+        let v2_pending = matches!(
+            self.transport,
+            Transport::V2Key(_) | Transport::V2Version { .. }
+        );
+        let fallback = v2_pending
+            && matches!(
+                err,
+                P2PError::PeerClosed(_)
+                    | P2PError::Io(_)
+                    | P2PError::Decode(_)
+                    | P2PError::ProtocolViolation(_)
             );
-            Ok(())
+        if fallback {
+            self.options.v2 = false;
+            self.transport = Transport::V1;
+            self.state = HandshakeState::default();
         }
+        fallback
     }
 }
 
-struct MessageReader {
-    stream: OwnedReadHalf,
-    buffer: BytesMut,
+/// The progress of the version handshake. We send our version first, then the peer
+/// answers with its own version and each side acknowledges the version of the other
+/// one with a verack. No other message is allowed before the peer version, and the
+/// version and verack messages cannot be repeated.
+#[derive(Debug, Default)]
+struct HandshakeState {
+    version_sent: bool,
+    version_received: bool,
+    verack_sent: bool,
+    verack_received: bool,
 }
 
-impl MessageReader {
-    pub fn new(stream: OwnedReadHalf, buff_size: usize) -> MessageReader {
-        MessageReader {
-            stream,
-            buffer: BytesMut::with_capacity(buff_size),
+impl HandshakeState {
+    /// Records a message sent to the peer.
+    fn on_sent(&mut self, message: &NetworkMessage) {
+        match message {
+            NetworkMessage::Version(_) => self.version_sent = true,
+            NetworkMessage::Verack => self.verack_sent = true,
+            _ => {}
         }
     }
-    pub async fn read_message(&mut self) -> Result<Option<RawNetworkMessage>, P2PError> {
-        loop {
-            if let Ok((message, count)) = deserialize_partial::<RawNetworkMessage>(&self.buffer) {
-                self.buffer.advance(count);
-                return Ok(Some(message));
-            }
 
-            if 0 == self.stream.read_buf(&mut self.buffer).await? {
-                if self.buffer.is_empty() {
-                    return Ok(None);
-                } else {
-                    return Err(P2PError {
-                        message: "connection reset by peer".into(),
-                    });
-                }
+    /// Validates and records a message received from the peer, returning the message
+    /// that must be sent as answer, if any.
+    fn on_received(
+        &mut self,
+        message: &NetworkMessage,
+    ) -> Result<Option<NetworkMessage>, P2PError> {
+        let violation = |reason: &str| {
+            Err(P2PError::ProtocolViolation(format!(
+                "{} message {}",
+                message.cmd(),
+                reason
+            )))
+        };
+        match message {
+            NetworkMessage::Version(_) if self.version_received => violation("received twice"),
+            NetworkMessage::Version(_) => {
+                self.version_received = true;
+                Ok(Some(NetworkMessage::Verack))
+            }
+            _ if !self.version_received => violation("received before the peer version"),
+            NetworkMessage::Verack if !self.version_sent => {
+                violation("received before sending our version")
             }
+            NetworkMessage::Verack if self.verack_received => violation("received twice"),
+            NetworkMessage::Verack => {
+                self.verack_received = true;
+                Ok(None)
+            }
+            _ => Ok(None),
         }
     }
+
+    /// Whether both versions were exchanged and acknowledged.
+    fn is_complete(&self) -> bool {
+        self.version_sent && self.version_received && self.verack_sent && self.verack_received
+    }
 }
 
-pub fn verack_message() -> RawNetworkMessage {
-    RawNetworkMessage {
-        magic: constants::Network::Bitcoin.magic(),
-        payload: NetworkMessage::Verack,
+fn supported_params(
+    target: &str,
+    chain: Chain,
+    network: Network,
+) -> Result<&'static ChainParams, P2PError> {
+    chain_params(chain, network).ok_or_else(|| P2PError::InvalidTarget {
+        target: target.to_string(),
+        reason: format!("the {} network is not supported by {}", network, chain),
+    })
+}
+
+fn check_magic(params: &ChainParams, magic: u32) -> Result<(), P2PError> {
+    if magic == params.magic {
+        return Ok(());
     }
+    let peer_network = match CHAINS.iter().find(|params| params.magic == magic) {
+        Some(peer_params) => peer_params.name,
+        None => "unknown",
+    };
+    Err(P2PError::WrongNetwork {
+        expected: format!("{} (magic {:#010x})", params.name, params.magic),
+        received: format!("{} (magic {:#010x})", peer_network, magic),
+    })
+}
+
+/// The round trip time statistics of the pings sent to a peer. The jitter is the mean
+/// difference between consecutive round trips.
+#[derive(Debug, PartialEq, Eq)]
+struct Latency {
+    min: Duration,
+    avg: Duration,
+    max: Duration,
+    jitter: Duration,
+}
+
+impl Latency {
+    fn from_rtts(rtts: &[Duration]) -> Option<Latency> {
+        let min = *rtts.iter().min()?;
+        let max = *rtts.iter().max()?;
+        let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        let jitter = match rtts.len() {
+            1 => Duration::ZERO,
+            len => {
+                rtts.windows(2)
+                    .map(|pair| pair[0].abs_diff(pair[1]))
+                    .sum::<Duration>()
+                    / (len - 1) as u32
+            }
+        };
+        Some(Latency {
+            min,
+            avg,
+            max,
+            jitter,
+        })
+    }
+}
+
+/// The offset in seconds of the peer clock to ours. The peer timestamp was taken when
+/// its version was sent, which is estimated as half the round trip before receiving it.
+fn clock_offset(peer_timestamp: i64, received_at: SystemTime, rtt: Duration) -> i64 {
+    let sent_at = received_at - rtt / 2;
+    let ours = match sent_at.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_secs_f64(),
+        Err(before_epoch) => -before_epoch.duration().as_secs_f64(),
+    };
+    (peer_timestamp as f64 - ours).round() as i64
 }
 
-pub fn version_message(dest_socket: String, user_agent: String) -> RawNetworkMessage {
+/// Builds our version message. The fields not configured take the defaults of the chain,
+/// or the ones of a node without services, at height 0 and not relaying transactions.
+fn version_message(
+    params: &ChainParams,
+    node_socket: SocketAddr,
+    user_agent: String,
+    version: &VersionConfig,
+) -> RawNetworkMessage {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs() as i64;
 
     let no_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
-    let node_socket = SocketAddr::from_str(&dest_socket).unwrap();
 
-    let btc_version = VersionMessage::new(
-        ServiceFlags::NONE,
+    let mut btc_version = VersionMessage::new(
+        version.services,
         now,
         address::Address::new(&node_socket, constants::ServiceFlags::NONE),
-        address::Address::new(&no_address, constants::ServiceFlags::NONE),
-        now as u64,
+        address::Address::new(&version.sender.unwrap_or(no_address), version.services),
+        version.nonce.unwrap_or(now as u64),
         user_agent,
-        0,
+        version.start_height,
     );
+    btc_version.version = version.protocol_version.unwrap_or(params.protocol_version);
+    btc_version.relay = version.relay;
 
     RawNetworkMessage {
-        magic: constants::Network::Bitcoin.magic(),
+        magic: params.magic,
         payload: NetworkMessage::Version(btc_version),
     }
 }
 
-impl From<SendError<RawNetworkMessage>> for P2PError {
-    fn from(send_err: SendError<RawNetworkMessage>) -> Self {
-        P2PError {
-            message: send_err.to_string(),
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::p2p::{
+        protocol::{
+            run,
+            tests::{peer, TIMEOUTS},
+        },
+        view::Value,
+    };
+
+    #[test]
+    fn handshake_state_completes_once_versions_are_acknowledged() {
+        let version = version_message(
+            &CHAINS[0],
+            "127.0.0.1:8333".parse().unwrap(),
+            "".into(),
+            &VersionConfig::default(),
+        );
+        let mut state = HandshakeState::default();
+
+        state.on_sent(&version.payload);
+        assert!(matches!(
+            state.on_received(&version.payload),
+            Ok(Some(NetworkMessage::Verack))
+        ));
+        assert!(!state.is_complete());
+        state.on_sent(&NetworkMessage::Verack);
+        assert!(!state.is_complete());
+        // Other messages are allowed once the peer version was received.
+        assert!(matches!(
+            state.on_received(&NetworkMessage::SendAddrV2),
+            Ok(None)
+        ));
+        assert!(matches!(
+            state.on_received(&NetworkMessage::Verack),
+            Ok(None)
+        ));
+        assert!(state.is_complete());
+    }
+
+    #[test]
+    fn handshake_state_rejects_out_of_order_and_repeated_messages() {
+        let version = version_message(
+            &CHAINS[0],
+            "127.0.0.1:8333".parse().unwrap(),
+            "".into(),
+            &VersionConfig::default(),
+        );
+        let cases = [
+            (
+                vec![],
+                NetworkMessage::Verack,
+                "verack message received before the peer version",
+            ),
+            (
+                vec![],
+                NetworkMessage::Ping(1),
+                "ping message received before the peer version",
+            ),
+            (
+                vec![version.payload.clone()],
+                version.payload.clone(),
+                "version message received twice",
+            ),
+            (
+                vec![version.payload.clone(), NetworkMessage::Verack],
+                NetworkMessage::Verack,
+                "verack message received twice",
+            ),
+        ];
+        for (received, offending, reason) in cases {
+            let mut state = HandshakeState::default();
+            state.on_sent(&version.payload);
+            for message in &received {
+                state.on_received(message).unwrap();
+            }
+            match state.on_received(&offending) {
+                Err(P2PError::ProtocolViolation(violation)) => assert_eq!(reason, violation),
+                res => panic!("expected protocol violation for {}, got {:?}", reason, res),
+            }
+        }
+    }
+
+    fn options() -> Options {
+        Options {
+            chain: Chain::Bitcoin,
+            network: Network::Bitcoin,
+            user_agent: "/Satoshi:23.0.0/".to_string(),
+            version: VersionConfig::default(),
+            v2: false,
+            max_clock_skew: 600,
+            pings: 0,
+            ping_timeout: 2000,
+        }
+    }
+
+    fn btc() -> Btc {
+        Btc::new("192.168.1.1", options()).unwrap()
+    }
+
+    #[test]
+    fn btc_applies_target_options() {
+        let mut btc = btc();
+
+        btc.apply_options(
+            "192.168.1.1",
+            &[
+                ("network".to_string(), "testnet".to_string()),
+                ("user_agent".to_string(), "/Satoshi:24.0.1/".to_string()),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(Network::Testnet, btc.options.network);
+        assert_eq!("/Satoshi:24.0.1/", btc.options.user_agent);
+        assert_eq!(18333, btc.default_port());
+    }
+
+    #[test]
+    fn btc_applies_version_target_options() {
+        let mut btc = btc();
+
+        btc.apply_options(
+            "192.168.1.1",
+            &[
+                ("protocol_version".to_string(), "70015".to_string()),
+                ("services".to_string(), "network,witness".to_string()),
+                ("start_height".to_string(), "800000".to_string()),
+                ("relay".to_string(), "true".to_string()),
+                ("sender".to_string(), "203.0.113.1:8333".to_string()),
+                ("nonce".to_string(), "42".to_string()),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            VersionConfig {
+                protocol_version: Some(70015),
+                services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+                start_height: 800000,
+                relay: true,
+                sender: Some("203.0.113.1:8333".parse().unwrap()),
+                nonce: Some(42),
+            },
+            btc.options.version
+        );
+    }
+
+    #[test]
+    fn btc_applies_chain_target_option() {
+        let mut btc = btc();
+
+        btc.apply_options(
+            "192.168.1.1",
+            &[
+                ("chain".to_string(), "dogecoin".to_string()),
+                ("network".to_string(), "testnet".to_string()),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(Chain::Dogecoin, btc.options.chain);
+        assert_eq!(44556, btc.default_port());
+        assert_eq!(0xdcb7c1fc, btc.params.magic);
+    }
+
+    #[test]
+    fn btc_rejects_unknown_target_options() {
+        let mut btc = btc();
+
+        for option in [
+            ("network", "mainnet"),
+            ("color", "blue"),
+            ("chain", "eth"),
+            ("services", "teleport"),
+            ("start_height", "tip"),
+            ("sender", "203.0.113.1"),
+        ] {
+            let option = [(option.0.to_string(), option.1.to_string())];
+            assert!(matches!(
+                btc.apply_options("192.168.1.1", &option),
+                Err(P2PError::InvalidTarget { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn btc_rejects_networks_not_supported_by_the_chain() {
+        let options = Options {
+            chain: Chain::Dash,
+            network: Network::Signet,
+            ..options()
+        };
+        let err = Btc::new("192.168.1.1", options);
+
+        match err {
+            Err(P2PError::InvalidTarget { reason, .. }) => {
+                assert_eq!("the signet network is not supported by dash", reason)
+            }
+            _ => panic!("expected invalid target"),
+        }
+    }
+
+    #[test]
+    fn chain_params_are_unique_and_match_bitcoin_ones() {
+        for (i, params) in CHAINS.iter().enumerate() {
+            assert_eq!(Some(params), chain_params(params.chain, params.network));
+            assert!(CHAINS[i + 1..]
+                .iter()
+                .all(|other| other.magic != params.magic));
+            if params.chain == Chain::Bitcoin {
+                assert_eq!(params.network.magic(), params.magic);
+                assert_eq!(params.network.to_string(), params.name);
+            }
+        }
+    }
+
+    #[test]
+    fn version_message_uses_chain_params() {
+        let params = chain_params(Chain::Litecoin, Network::Bitcoin).unwrap();
+
+        let version = version_message(
+            params,
+            "127.0.0.1:9333".parse().unwrap(),
+            "".into(),
+            &VersionConfig::default(),
+        );
+
+        assert_eq!(0xdbb6c0fb, version.magic);
+        match version.payload {
+            NetworkMessage::Version(version) => assert_eq!(70016, version.version),
+            _ => panic!("expected version message"),
         }
     }
+
+    #[test]
+    fn version_message_uses_configured_fields() {
+        let params = chain_params(Chain::Bitcoin, Network::Bitcoin).unwrap();
+        let config = VersionConfig {
+            protocol_version: Some(70015),
+            services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+            start_height: 800000,
+            relay: true,
+            sender: Some("203.0.113.1:8333".parse().unwrap()),
+            nonce: Some(42),
+        };
+
+        let version = version_message(
+            params,
+            "127.0.0.1:8333".parse().unwrap(),
+            "".into(),
+            &config,
+        );
+
+        match version.payload {
+            NetworkMessage::Version(version) => {
+                assert_eq!(70015, version.version);
+                assert_eq!(config.services, version.services);
+                assert_eq!(800000, version.start_height);
+                assert!(version.relay);
+                assert_eq!(
+                    "203.0.113.1:8333".parse::<SocketAddr>().unwrap(),
+                    version.sender.socket_addr().unwrap()
+                );
+                assert_eq!(42, version.nonce);
+            }
+            _ => panic!("expected version message"),
+        }
+    }
+
+    #[test]
+    fn parse_services_accepts_names_and_values() {
+        let services =
+            ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::NETWORK_LIMITED;
+        for s in [
+            "network,witness,network_limited",
+            "NETWORK, witness, network_limited",
+            "1033",
+            "0x409",
+        ] {
+            assert_eq!(Ok(services), parse_services(s), "{}", s);
+        }
+        assert_eq!(Ok(ServiceFlags::NONE), parse_services("none"));
+        assert!(parse_services("network,teleport").is_err());
+    }
+
+    #[test]
+    fn check_magic_accepts_configured_network() {
+        let params = chain_params(Chain::Bitcoin, Network::Testnet).unwrap();
+
+        assert!(check_magic(params, Network::Testnet.magic()).is_ok());
+    }
+
+    #[test]
+    fn check_magic_reports_wrong_network() {
+        let params = chain_params(Chain::Bitcoin, Network::Bitcoin).unwrap();
+
+        let err = check_magic(params, Network::Testnet.magic()).unwrap_err();
+
+        assert!(matches!(err, P2PError::WrongNetwork { .. }));
+        assert_eq!(
+            "P2P error: wrong network: expected bitcoin (magic 0xd9b4bef9), peer answered with testnet (magic 0x0709110b)",
+            err.to_string()
+        );
+        let err = check_magic(params, 0xdbb6c0fb).unwrap_err();
+        assert_eq!(
+            "P2P error: wrong network: expected bitcoin (magic 0xd9b4bef9), peer answered with litecoin (magic 0xdbb6c0fb)",
+            err.to_string()
+        );
+    }
+
+    fn btc_v2() -> Btc {
+        let mut btc = btc();
+        btc.options.v2 = true;
+        btc
+    }
+
+    /// Reads from the stream until the parser finds what it looks for in the buffer.
+    async fn read_until<T>(
+        stream: &mut TcpStream,
+        buffer: &mut BytesMut,
+        mut parse: impl FnMut(&mut BytesMut) -> Option<T>,
+    ) -> T {
+        loop {
+            if let Some(parsed) = parse(buffer) {
+                return parsed;
+            }
+            assert_ne!(0, stream.read_buf(buffer).await.unwrap(), "peer closed");
+        }
+    }
+
+    fn peer_version(params: &ChainParams) -> RawNetworkMessage {
+        let config = VersionConfig {
+            protocol_version: Some(70016),
+            services: parse_services("network,witness,network_limited,p2p_v2").unwrap(),
+            start_height: 850000,
+            relay: true,
+            sender: Some("[2001:db8::1]:8333".parse().unwrap()),
+            nonce: Some(42),
+        };
+        version_message(
+            params,
+            "203.0.113.1:50000".parse().unwrap(),
+            "/Satoshi:26.0.0/".into(),
+            &config,
+        )
+    }
+
+    fn next_message(buffer: &mut BytesMut) -> Option<RawNetworkMessage> {
+        match deserialize_partial::<RawNetworkMessage>(buffer) {
+            Ok((message, count)) => {
+                buffer.advance(count);
+                Some(message)
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Answers the v1 handshake of the connected node with the provided version,
+    /// returning the connection once our verack is received.
+    async fn v1_peer(mut stream: TcpStream, version: RawNetworkMessage) -> (TcpStream, BytesMut) {
+        let params = &CHAINS[0];
+        let mut buffer = BytesMut::new();
+        read_until(&mut stream, &mut buffer, next_message).await;
+        let verack = RawNetworkMessage {
+            magic: params.magic,
+            payload: NetworkMessage::Verack,
+        };
+        let mut answer = serialize(&version);
+        answer.extend(serialize(&verack));
+        stream.write_all(&answer).await.unwrap();
+        read_until(&mut stream, &mut buffer, next_message).await;
+        (stream, buffer)
+    }
+
+    /// Answers the v2 handshake of the connected node, sending a decoy before its version packet.
+    async fn v2_peer(mut stream: TcpStream) {
+        let params = &CHAINS[0];
+        let mut buffer = BytesMut::new();
+        let handshake = Handshake::responder(params.magic);
+        stream.write_all(&handshake.key()).await.unwrap();
+        let key = read_until(&mut stream, &mut buffer, |buffer| {
+            (buffer.len() >= KEY_LEN).then(|| buffer.split_to(KEY_LEN))
+        })
+        .await;
+        let (mut cipher, packets) = handshake.complete(key[..].try_into().unwrap(), &[b"decoy"]);
+        stream.write_all(&packets).await.unwrap();
+
+        read_until(&mut stream, &mut buffer, |buffer| {
+            cipher.read_garbage(buffer).unwrap()
+        })
+        .await;
+        // Our version packet and version message.
+        for _ in 0..2 {
+            read_until(&mut stream, &mut buffer, |buffer| {
+                cipher.decrypt(buffer).unwrap()
+            })
+            .await;
+        }
+        let verack = RawNetworkMessage {
+            magic: params.magic,
+            payload: NetworkMessage::Verack,
+        };
+        let mut answer = cipher.encrypt(&v2::encode_message(&peer_version(params)));
+        answer.extend(cipher.encrypt(&v2::encode_message(&verack)));
+        stream.write_all(&answer).await.unwrap();
+        let verack = read_until(&mut stream, &mut buffer, |buffer| {
+            cipher.decrypt(buffer).unwrap()
+        })
+        .await;
+        assert_eq!(
+            NetworkMessage::Verack,
+            v2::decode_message(params.magic, &verack.contents)
+                .unwrap()
+                .payload
+        );
+    }
+
+    fn pair(result: &crate::p2p::view::HandshakeResult, name: &str, key: &str) -> String {
+        result
+            .event_chain()
+            .events()
+            .iter()
+            .filter(|ev| ev.name() == name)
+            .find_map(|ev| ev.pair(key))
+            .unwrap()
+            .to_string()
+    }
+
+    fn names(result: &crate::p2p::view::HandshakeResult) -> Vec<&str> {
+        result
+            .event_chain()
+            .events()
+            .iter()
+            .map(|ev| ev.name())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn btc_handshakes_with_v2_peers() {
+        let (addr, peer) = peer(v2_peer).await;
+
+        let result = run(addr.to_string(), TIMEOUTS, btc_v2()).await;
+
+        assert!(result.event_chain().is_complete(), "{:?}", result.error());
+        peer.await.unwrap();
+        assert!(!names(&result).contains(&"retry"));
+        assert_eq!("v2", pair(&result, "version", "transport"));
+        assert_eq!("1", pair(&result, "v2-version", "decoys"));
+    }
+
+    #[tokio::test]
+    async fn btc_records_the_peer_version() {
+        let (addr, peer) = peer(|stream| v1_peer(stream, peer_version(&CHAINS[0]))).await;
+
+        let result = run(addr.to_string(), TIMEOUTS, btc()).await;
+
+        assert!(result.event_chain().is_complete(), "{:?}", result.error());
+        peer.await.unwrap();
+        let version = result
+            .event_chain()
+            .events()
+            .iter()
+            .find(|ev| ev.name() == "version" && matches!(ev.direction(), EventDirection::IN))
+            .unwrap();
+        let services = ["NETWORK", "WITNESS", "NETWORK_LIMITED", "P2P_V2"];
+        let cases: [(&str, Value); 8] = [
+            ("vers", 70016u32.into()),
+            ("user-agent", "/Satoshi:26.0.0/".into()),
+            ("services", services.map(String::from).to_vec().into()),
+            ("receiver", "203.0.113.1:50000".into()),
+            ("sender", "[2001:db8::1]:8333".into()),
+            ("nonce", 42u64.into()),
+            ("start-height", 850000.into()),
+            ("relay", true.into()),
+        ];
+        for (key, val) in cases {
+            assert_eq!(Some(&val), version.pair(key), "{}", key);
+        }
+        assert!(matches!(version.pair("timestamp"), Some(Value::Int(_))));
+    }
+
+    #[tokio::test]
+    async fn btc_flags_peers_with_skewed_clocks() {
+        let mut version = peer_version(&CHAINS[0]);
+        if let NetworkMessage::Version(version) = &mut version.payload {
+            version.timestamp -= 3600;
+        }
+        let (addr, peer) = peer(|stream| v1_peer(stream, version)).await;
+
+        let result = run(addr.to_string(), TIMEOUTS, btc()).await;
+
+        assert!(result.event_chain().is_complete(), "{:?}", result.error());
+        peer.await.unwrap();
+        let offset: i64 = pair(&result, "clock", "offset-s").parse().unwrap();
+        assert!((-3601..=-3599).contains(&offset), "{}", offset);
+        assert_eq!("true", pair(&result, "clock", "skewed"));
+    }
+
+    #[tokio::test]
+    async fn btc_measures_the_latency_with_pings() {
+        let (addr, peer) = peer(|stream| async move {
+            let (mut stream, mut buffer) = v1_peer(stream, peer_version(&CHAINS[0])).await;
+            let message = |payload| {
+                serialize(&RawNetworkMessage {
+                    magic: CHAINS[0].magic,
+                    payload,
+                })
+            };
+            // Peers ping us too, and announce their preferences once the handshake completes.
+            let mut announces = message(NetworkMessage::SendHeaders);
+            announces.extend(message(NetworkMessage::Ping(7)));
+            stream.write_all(&announces).await.unwrap();
+            let (mut pings, mut pong) = (Vec::new(), None);
+            while pings.len() < 3 || pong.is_none() {
+                match read_until(&mut stream, &mut buffer, next_message)
+                    .await
+                    .payload
+                {
+                    NetworkMessage::Ping(nonce) => {
+                        stream
+                            .write_all(&message(NetworkMessage::Pong(nonce)))
+                            .await
+                            .unwrap();
+                        pings.push(nonce);
+                    }
+                    NetworkMessage::Pong(nonce) => pong = Some(nonce),
+                    payload => panic!("unexpected message {:?}", payload),
+                }
+            }
+            (pings, pong)
+        })
+        .await;
+
+        let mut btc = btc();
+        btc.apply_options("192.168.1.1", &[("pings".to_string(), "3".to_string())])
+            .unwrap();
+        let result = run(addr.to_string(), TIMEOUTS, btc).await;
+
+        assert!(result.event_chain().is_complete(), "{:?}", result.error());
+        let (pings, pong) = peer.await.unwrap();
+        assert_eq!(Some(7), pong);
+        let events = result.event_chain().events();
+        let nonces = |name: &str, out: bool| -> Vec<String> {
+            events
+                .iter()
+                .filter(|ev| ev.name() == name)
+                .filter(|ev| matches!(ev.direction(), EventDirection::OUT) == out)
+                .map(|ev| ev.pair("nonce").unwrap().to_string())
+                .collect()
+        };
+        let pings: Vec<String> = pings.iter().map(u64::to_string).collect();
+        assert_eq!(pings, nonces("ping", true));
+        assert_eq!(pings, nonces("pong", false));
+        assert_eq!(vec!["7"], nonces("ping", false));
+        assert_eq!(vec!["7"], nonces("pong", true));
+        assert!(!names(&result).contains(&"sendheaders"));
+        assert!(events
+            .iter()
+            .filter(|ev| ev.name() == "pong")
+            .filter(|ev| !matches!(ev.direction(), EventDirection::OUT))
+            .all(|ev| ev.pair("matched").unwrap().to_string() == "true"));
+        assert_eq!("3", pair(&result, "latency", "pings-sent"));
+        assert_eq!("3", pair(&result, "latency", "pongs-received"));
+        let latency: Vec<u64> = ["min-us", "avg-us", "max-us"]
+            .iter()
+            .map(|key| pair(&result, "latency", key).parse().unwrap())
+            .collect();
+        assert!(latency[0] <= latency[1] && latency[1] <= latency[2]);
+    }
+
+    #[tokio::test]
+    async fn btc_records_the_missing_pongs_without_failing() {
+        let (addr, peer) = peer(|stream| async move {
+            let (mut stream, mut buffer) = v1_peer(stream, peer_version(&CHAINS[0])).await;
+            let message = |payload| {
+                serialize(&RawNetworkMessage {
+                    magic: CHAINS[0].magic,
+                    payload,
+                })
+            };
+            // Only the first ping is answered, after a pong nobody asked for.
+            let mut pings = Vec::new();
+            while pings.len() < 2 {
+                if let NetworkMessage::Ping(nonce) =
+                    read_until(&mut stream, &mut buffer, next_message)
+                        .await
+                        .payload
+                {
+                    if pings.is_empty() {
+                        let mut pongs = message(NetworkMessage::Pong(nonce.wrapping_add(1)));
+                        pongs.extend(message(NetworkMessage::Pong(nonce)));
+                        stream.write_all(&pongs).await.unwrap();
+                    }
+                    pings.push(nonce);
+                }
+            }
+            // Keep the connection open until the ping timeout.
+            let _ = stream.read(&mut [0; 1]).await;
+        })
+        .await;
+
+        let mut btc = btc();
+        btc.apply_options(
+            "192.168.1.1",
+            &[
+                ("pings".to_string(), "3".to_string()),
+                ("ping_timeout".to_string(), "200".to_string()),
+            ],
+        )
+        .unwrap();
+        let result = run(addr.to_string(), TIMEOUTS, btc).await;
+
+        assert!(result.event_chain().is_complete(), "{:?}", result.error());
+        assert!(result.error().is_none());
+        peer.abort();
+        let matched: Vec<String> = result
+            .event_chain()
+            .events()
+            .iter()
+            .filter(|ev| ev.name() == "pong")
+            .filter(|ev| !matches!(ev.direction(), EventDirection::OUT))
+            .map(|ev| ev.pair("matched").unwrap().to_string())
+            .collect();
+        assert_eq!(vec!["false", "true"], matched);
+        assert_eq!("2", pair(&result, "latency", "pings-sent"));
+        assert_eq!("1", pair(&result, "latency", "pongs-received"));
+    }
+
+    #[test]
+    fn latency_is_computed_from_the_round_trips() {
+        let ms = Duration::from_millis;
+
+        assert_eq!(None, Latency::from_rtts(&[]));
+        assert_eq!(
+            Some(Latency {
+                min: ms(20),
+                avg: ms(20),
+                max: ms(20),
+                jitter: ms(0),
+            }),
+            Latency::from_rtts(&[ms(20)])
+        );
+        assert_eq!(
+            Some(Latency {
+                min: ms(10),
+                avg: ms(20),
+                max: ms(40),
+                jitter: ms(20),
+            }),
+            Latency::from_rtts(&[ms(10), ms(20), ms(40), ms(10)])
+        );
+    }
+
+    #[test]
+    fn clock_offset_is_corrected_by_half_the_round_trip() {
+        let received_at = UNIX_EPOCH + Duration::from_secs(1_700_000_010);
+
+        assert_eq!(0, clock_offset(1_700_000_010, received_at, Duration::ZERO));
+        assert_eq!(
+            0,
+            clock_offset(1_700_000_009, received_at, Duration::from_secs(2))
+        );
+        assert_eq!(
+            -600,
+            clock_offset(1_699_999_409, received_at, Duration::from_secs(2))
+        );
+        assert_eq!(
+            30,
+            clock_offset(1_700_000_040, received_at, Duration::from_millis(300))
+        );
+    }
+
+    #[test]
+    fn service_names_follow_bitcoin_core_ones() {
+        assert_eq!(vec!["NONE"], service_names(ServiceFlags::NONE));
+        assert_eq!(
+            vec!["NETWORK", "BLOOM", "COMPACT_FILTERS", "UNKNOWN[2^12]"],
+            service_names(ServiceFlags::from(1 | 1 << 2 | 1 << 6 | 1 << 12))
+        );
+    }
+
+    #[tokio::test]
+    async fn btc_falls_back_to_v1_when_v2_is_not_supported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let peer = tokio::spawn(async move {
+            // Like v1 peers do, the connection is closed as the key is not a v1 message.
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.read_exact(&mut [0; 24]).await.unwrap();
+            drop(stream);
+            v1_peer(listener.accept().await.unwrap().0, peer_version(&CHAINS[0])).await
+        });
+
+        let result = run(addr, TIMEOUTS, btc_v2()).await;
+
+        assert!(result.event_chain().is_complete(), "{:?}", result.error());
+        peer.await.unwrap();
+        let names = names(&result);
+        let retry = names.iter().position(|name| *name == "retry").unwrap();
+        assert!(names[..retry].contains(&"v2-key"));
+        assert_eq!(["closed", "retry", "resolve"], names[retry - 1..retry + 2]);
+        assert_eq!("peer_closed", pair(&result, "retry", "reason"));
+        assert_eq!("v1", pair(&result, "version", "transport"));
+    }
 }
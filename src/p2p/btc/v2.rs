@@ -0,0 +1,587 @@
+// The BIP324 v2 encrypted transport: the ElligatorSwift encoded key exchange, the garbage
+// and garbage terminators, the version packets and the encryption of the messages sent
+// after them. See <https://github.com/bitcoin/bips/blob/master/bip-0324.mediawiki>.
+//
+// Both sides of the handshake are implemented, although the responder one is only used by tests.
+
+use bitcoin::{
+    consensus::{deserialize, serialize},
+    hashes::{sha256d, Hash},
+    network::message::RawNetworkMessage,
+};
+use bytes::BytesMut;
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, KeyInit, Tag};
+use hkdf::Hkdf;
+use rand::{thread_rng, Rng};
+use secp256k1_ellswift::{
+    ellswift::{ElligatorSwift, ElligatorSwiftParty},
+    Secp256k1, SecretKey,
+};
+use sha2::Sha256;
+
+use crate::p2p::P2PError;
+
+/// The length of the ElligatorSwift encoded keys.
+pub const KEY_LEN: usize = 64;
+const TERMINATOR_LEN: usize = 16;
+/// The garbage sent before the terminator can be up to 4095 bytes long. We send way less,
+/// as it only hides the shape of the traffic.
+const MAX_GARBAGE_LEN: usize = 4095;
+const SENT_GARBAGE_LEN: usize = 32;
+
+const LENGTH_LEN: usize = 3;
+const HEADER_LEN: usize = 1;
+const TAG_LEN: usize = 16;
+/// The flag of the header byte marking the packets to be ignored.
+const DECOY_FLAG: u8 = 0x80;
+
+/// The longest packet we accept, as Bitcoin Core limits the messages to 4MB.
+const MAX_CONTENTS_LEN: usize = 4_000_000 + 13;
+
+/// Both ciphers derive a new key every 224 messages.
+const REKEY_INTERVAL: u64 = 224;
+
+/// The message types with a short id, in the position of their id minus one. The rest
+/// of them are sent with their 12 bytes v1 command.
+const SHORT_IDS: [&str; 28] = [
+    "addr",
+    "block",
+    "blocktxn",
+    "cmpctblock",
+    "feefilter",
+    "filteradd",
+    "filterclear",
+    "filterload",
+    "getblocks",
+    "getblocktxn",
+    "getdata",
+    "getheaders",
+    "headers",
+    "inv",
+    "mempool",
+    "merkleblock",
+    "notfound",
+    "ping",
+    "pong",
+    "sendcmpct",
+    "tx",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "addrv2",
+];
+
+/// The v1 header length: magic, command, payload length and checksum.
+const V1_HEADER_LEN: usize = 24;
+const COMMAND_LEN: usize = 12;
+
+/// The state of the handshake once our key was created.
+pub struct Handshake {
+    magic: u32,
+    initiator: bool,
+    secret_key: SecretKey,
+    key: ElligatorSwift,
+    garbage: Vec<u8>,
+}
+
+impl Handshake {
+    /// Prepares the handshake of the initiator side on the network of the provided magic,
+    /// with a new key and random garbage.
+    pub fn new(magic: u32) -> Handshake {
+        Handshake::with_role(magic, true)
+    }
+
+    #[cfg(test)]
+    pub fn responder(magic: u32) -> Handshake {
+        Handshake::with_role(magic, false)
+    }
+
+    fn with_role(magic: u32, initiator: bool) -> Handshake {
+        let secret_key = SecretKey::new(&mut thread_rng());
+        let mut garbage = vec![0; thread_rng().gen_range(0..=SENT_GARBAGE_LEN)];
+        thread_rng().fill(&mut garbage[..]);
+        Handshake {
+            magic,
+            initiator,
+            secret_key,
+            key: ElligatorSwift::from_seckey(
+                &Secp256k1::new(),
+                secret_key,
+                Some(thread_rng().gen()),
+            ),
+            garbage,
+        }
+    }
+
+    /// Our key followed by our garbage, the first bytes sent to the peer.
+    pub fn key(&self) -> Vec<u8> {
+        let mut key = self.key.to_array().to_vec();
+        key.extend_from_slice(&self.garbage);
+        key
+    }
+
+    /// Derives the ciphers from the key of the peer. Returns them along our garbage
+    /// terminator, decoy packets and version packet, which are sent next.
+    pub fn complete(self, peer_key: [u8; KEY_LEN], decoys: &[&[u8]]) -> (Cipher, Vec<u8>) {
+        let peer_key = ElligatorSwift::from_array(peer_key);
+        let keys = match self.initiator {
+            true => SessionKeys::new(
+                self.key,
+                peer_key,
+                self.secret_key,
+                ElligatorSwiftParty::A,
+                self.magic,
+            ),
+            false => SessionKeys::new(
+                peer_key,
+                self.key,
+                self.secret_key,
+                ElligatorSwiftParty::B,
+                self.magic,
+            ),
+        };
+        let mut cipher = Cipher::new(keys, self.initiator, self.garbage);
+        let mut packets = cipher.terminator.to_vec();
+        for decoy in decoys {
+            packets.extend(cipher.encrypt_packet(DECOY_FLAG, decoy));
+        }
+        // The version packet has no contents in the current version of the protocol.
+        packets.extend(cipher.encrypt(&[]));
+        (cipher, packets)
+    }
+}
+
+/// The keys derived from the shared secret of both sides.
+struct SessionKeys {
+    session_id: [u8; 32],
+    initiator_length: [u8; 32],
+    initiator_packet: [u8; 32],
+    responder_length: [u8; 32],
+    responder_packet: [u8; 32],
+    initiator_terminator: [u8; TERMINATOR_LEN],
+    responder_terminator: [u8; TERMINATOR_LEN],
+}
+
+impl SessionKeys {
+    fn new(
+        initiator_key: ElligatorSwift,
+        responder_key: ElligatorSwift,
+        secret_key: SecretKey,
+        party: ElligatorSwiftParty,
+        magic: u32,
+    ) -> SessionKeys {
+        // The BIP324 tagged hash of the x-only ECDH is always used.
+        let shared_secret =
+            ElligatorSwift::shared_secret(initiator_key, responder_key, secret_key, party, None);
+        let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+        salt.extend_from_slice(&magic.to_le_bytes());
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), &shared_secret.to_secret_bytes());
+        let expand = |info: &str| {
+            let mut okm = [0; 32];
+            hkdf.expand(info.as_bytes(), &mut okm)
+                .expect("32 bytes is a valid HKDF-SHA256 length");
+            okm
+        };
+        let terminators = expand("garbage_terminators");
+        SessionKeys {
+            session_id: expand("session_id"),
+            initiator_length: expand("initiator_L"),
+            initiator_packet: expand("initiator_P"),
+            responder_length: expand("responder_L"),
+            responder_packet: expand("responder_P"),
+            initiator_terminator: terminators[..TERMINATOR_LEN].try_into().expect("16 bytes"),
+            responder_terminator: terminators[TERMINATOR_LEN..].try_into().expect("16 bytes"),
+        }
+    }
+}
+
+/// A packet received from the peer.
+pub struct Packet {
+    /// Decoy packets only hide the shape of the traffic, so they must be ignored.
+    pub decoy: bool,
+    pub contents: Vec<u8>,
+}
+
+/// Encrypts and decrypts the packets once the keys were exchanged.
+pub struct Cipher {
+    session_id: [u8; 32],
+    sending: PacketCipher,
+    receiving: PacketCipher,
+    terminator: [u8; TERMINATOR_LEN],
+    peer_terminator: [u8; TERMINATOR_LEN],
+    /// The garbage of each side, authenticated by the first packet sent after it.
+    garbage: Option<Vec<u8>>,
+    peer_garbage: Option<Vec<u8>>,
+    /// The length of the contents of the packet being received, once decrypted.
+    contents_len: Option<usize>,
+}
+
+impl Cipher {
+    fn new(keys: SessionKeys, initiator: bool, garbage: Vec<u8>) -> Cipher {
+        let initiator_cipher = PacketCipher::new(&keys.initiator_length, &keys.initiator_packet);
+        let responder_cipher = PacketCipher::new(&keys.responder_length, &keys.responder_packet);
+        let (sending, receiving, terminator, peer_terminator) = match initiator {
+            true => (
+                initiator_cipher,
+                responder_cipher,
+                keys.initiator_terminator,
+                keys.responder_terminator,
+            ),
+            false => (
+                responder_cipher,
+                initiator_cipher,
+                keys.responder_terminator,
+                keys.initiator_terminator,
+            ),
+        };
+        Cipher {
+            session_id: keys.session_id,
+            sending,
+            receiving,
+            terminator,
+            peer_terminator,
+            garbage: Some(garbage),
+            peer_garbage: None,
+            contents_len: None,
+        }
+    }
+
+    /// The identifier of the session, the same for both sides.
+    pub fn session_id(&self) -> &[u8; 32] {
+        &self.session_id
+    }
+
+    /// Encrypts the contents in a genuine packet.
+    pub fn encrypt(&mut self, contents: &[u8]) -> Vec<u8> {
+        self.encrypt_packet(0, contents)
+    }
+
+    fn encrypt_packet(&mut self, header: u8, contents: &[u8]) -> Vec<u8> {
+        let aad = self.garbage.take().unwrap_or_default();
+        let mut packet = (contents.len() as u32).to_le_bytes()[..LENGTH_LEN].to_vec();
+        self.sending.crypt_length(&mut packet);
+        let mut plaintext = vec![header];
+        plaintext.extend_from_slice(contents);
+        packet.extend(self.sending.encrypt(&aad, plaintext));
+        packet
+    }
+
+    /// Takes the garbage of the peer and its terminator from the buffer, returning the
+    /// length of the garbage, or nothing if more data is needed.
+    pub fn read_garbage(&mut self, buffer: &mut BytesMut) -> Result<Option<usize>, P2PError> {
+        let searched = &buffer[..buffer.len().min(MAX_GARBAGE_LEN + TERMINATOR_LEN)];
+        match searched
+            .windows(TERMINATOR_LEN)
+            .position(|window| window == self.peer_terminator)
+        {
+            Some(garbage_len) => {
+                let garbage = buffer.split_to(garbage_len).to_vec();
+                let _ = buffer.split_to(TERMINATOR_LEN);
+                self.peer_garbage = Some(garbage);
+                Ok(Some(garbage_len))
+            }
+            None if searched.len() == MAX_GARBAGE_LEN + TERMINATOR_LEN => Err(
+                P2PError::ProtocolViolation("garbage terminator not found".to_string()),
+            ),
+            None => Ok(None),
+        }
+    }
+
+    /// Takes the next packet from the buffer, or nothing if more data is needed.
+    pub fn decrypt(&mut self, buffer: &mut BytesMut) -> Result<Option<Packet>, P2PError> {
+        let contents_len = match self.contents_len {
+            Some(contents_len) => contents_len,
+            None if buffer.len() < LENGTH_LEN => return Ok(None),
+            None => {
+                let mut length = buffer.split_to(LENGTH_LEN);
+                self.receiving.crypt_length(&mut length);
+                let contents_len =
+                    u32::from_le_bytes([length[0], length[1], length[2], 0]) as usize;
+                if contents_len > MAX_CONTENTS_LEN {
+                    return Err(P2PError::Decode(
+                        format!("packet of {} bytes is too long", contents_len).into(),
+                    ));
+                }
+                self.contents_len = Some(contents_len);
+                contents_len
+            }
+        };
+        if buffer.len() < HEADER_LEN + contents_len + TAG_LEN {
+            return Ok(None);
+        }
+        self.contents_len = None;
+        let aad = self.peer_garbage.take().unwrap_or_default();
+        let ciphertext = buffer.split_to(HEADER_LEN + contents_len + TAG_LEN);
+        let mut plaintext = self.receiving.decrypt(&aad, &ciphertext)?;
+        let contents = plaintext.split_off(HEADER_LEN);
+        Ok(Some(Packet {
+            decoy: plaintext[0] & DECOY_FLAG != 0,
+            contents,
+        }))
+    }
+}
+
+/// The ciphers of a single direction: the lengths are encrypted by a ChaCha20 stream,
+/// and the header and contents by ChaCha20Poly1305. Both are rekeyed periodically.
+struct PacketCipher {
+    length: ChaCha20,
+    lengths: u64,
+    packet_key: [u8; 32],
+    packets: u64,
+}
+
+impl PacketCipher {
+    fn new(length_key: &[u8; 32], packet_key: &[u8; 32]) -> PacketCipher {
+        PacketCipher {
+            length: ChaCha20::new(length_key.into(), &rekey_nonce(0).into()),
+            lengths: 0,
+            packet_key: *packet_key,
+            packets: 0,
+        }
+    }
+
+    /// Encrypts or decrypts a length, which are a continuous key stream until rekeyed.
+    fn crypt_length(&mut self, length: &mut [u8]) {
+        self.length.apply_keystream(length);
+        self.lengths += 1;
+        if self.lengths % REKEY_INTERVAL == 0 {
+            let mut key = [0; 32];
+            self.length.apply_keystream(&mut key);
+            let nonce = rekey_nonce(self.lengths / REKEY_INTERVAL);
+            self.length = ChaCha20::new(&key.into(), &nonce.into());
+        }
+    }
+
+    /// Encrypts the packet, returning it along its tag.
+    fn encrypt(&mut self, aad: &[u8], mut plaintext: Vec<u8>) -> Vec<u8> {
+        let tag = ChaCha20Poly1305::new(&self.packet_key.into())
+            .encrypt_in_place_detached(&self.packet_nonce().into(), aad, &mut plaintext)
+            .expect("packets are way shorter than the ChaChaPoly limit");
+        plaintext.extend_from_slice(&tag);
+        self.next_packet();
+        plaintext
+    }
+
+    fn decrypt(&mut self, aad: &[u8], packet: &[u8]) -> Result<Vec<u8>, P2PError> {
+        let (ciphertext, tag) = packet.split_at(packet.len() - TAG_LEN);
+        let mut plaintext = ciphertext.to_vec();
+        ChaCha20Poly1305::new(&self.packet_key.into())
+            .decrypt_in_place_detached(
+                &self.packet_nonce().into(),
+                aad,
+                &mut plaintext,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| P2PError::Decode("packet authentication failed".into()))?;
+        self.next_packet();
+        Ok(plaintext)
+    }
+
+    /// The 96 bits nonce: the little endian counter of the packets since the last rekey,
+    /// followed by the little endian counter of rekeys.
+    fn packet_nonce(&self) -> [u8; 12] {
+        let mut nonce = rekey_nonce(self.packets / REKEY_INTERVAL);
+        nonce[..4].copy_from_slice(&((self.packets % REKEY_INTERVAL) as u32).to_le_bytes());
+        nonce
+    }
+
+    /// The new key is the encryption of 32 zero bytes with a special nonce.
+    fn next_packet(&mut self) {
+        self.packets += 1;
+        if self.packets % REKEY_INTERVAL == 0 {
+            let mut nonce = rekey_nonce(self.packets / REKEY_INTERVAL - 1);
+            nonce[..4].copy_from_slice(&[0xff; 4]);
+            let mut key = [0; 32];
+            let _ = ChaCha20Poly1305::new(&self.packet_key.into())
+                .encrypt_in_place_detached(&nonce.into(), &[], &mut key)
+                .expect("32 bytes are shorter than the ChaChaPoly limit");
+            self.packet_key = key;
+        }
+    }
+}
+
+/// The 96 bits nonce: 32 zero bits followed by the little endian counter of rekeys.
+fn rekey_nonce(rekeys: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&rekeys.to_le_bytes());
+    nonce
+}
+
+/// The contents of the packet of a message: its short id, or a zero followed by its v1
+/// command, and its payload.
+pub fn encode_message(message: &RawNetworkMessage) -> Vec<u8> {
+    let v1 = serialize(message);
+    let command = message.cmd();
+    let mut contents = match SHORT_IDS.iter().position(|short| *short == command) {
+        Some(position) => vec![position as u8 + 1],
+        None => {
+            let mut contents = vec![0];
+            contents.extend_from_slice(&v1[4..4 + COMMAND_LEN]);
+            contents
+        }
+    };
+    contents.extend_from_slice(&v1[V1_HEADER_LEN..]);
+    contents
+}
+
+/// Decodes the message from the contents of a packet, by rebuilding its v1 framing.
+pub fn decode_message(magic: u32, contents: &[u8]) -> Result<RawNetworkMessage, P2PError> {
+    let (command, payload) = match contents.split_first() {
+        Some((0, rest)) if rest.len() >= COMMAND_LEN => {
+            let (command, payload) = rest.split_at(COMMAND_LEN);
+            (command.to_vec(), payload)
+        }
+        Some((&short_id, payload)) if (1..=SHORT_IDS.len()).contains(&(short_id as usize)) => {
+            let mut command = SHORT_IDS[short_id as usize - 1].as_bytes().to_vec();
+            command.resize(COMMAND_LEN, 0);
+            (command, payload)
+        }
+        Some((short_id, _)) => {
+            return Err(P2PError::Decode(
+                format!("unknown message short id {}", short_id).into(),
+            ))
+        }
+        None => return Err(P2PError::Decode("empty message".into())),
+    };
+    let mut v1 = magic.to_le_bytes().to_vec();
+    v1.extend(command);
+    v1.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    v1.extend_from_slice(&sha256d::Hash::hash(payload)[..4]);
+    v1.extend_from_slice(payload);
+    deserialize(&v1).map_err(|err| P2PError::Decode(Box::new(err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::network::message::NetworkMessage;
+
+    use super::*;
+
+    const MAGIC: u32 = 0xd9b4bef9;
+
+    fn array<const N: usize>(hex: &str) -> [u8; N] {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    fn cipher(
+        initiator: bool,
+        secret_key: &str,
+        initiator_key: &str,
+        responder_key: &str,
+    ) -> Cipher {
+        let party = match initiator {
+            true => ElligatorSwiftParty::A,
+            false => ElligatorSwiftParty::B,
+        };
+        let keys = SessionKeys::new(
+            ElligatorSwift::from_array(array(initiator_key)),
+            ElligatorSwift::from_array(array(responder_key)),
+            SecretKey::from_slice(&hex::decode(secret_key).unwrap()).unwrap(),
+            party,
+            MAGIC,
+        );
+        Cipher::new(keys, initiator, Vec::new())
+    }
+
+    /// The first two vectors of the BIP324 packet encoding test vectors.
+    #[test]
+    fn ciphers_follow_the_bip324_test_vectors() {
+        let mut initiator = cipher(
+            true,
+            "61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7",
+            "ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa186f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b",
+            "a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafaffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5",
+        );
+        initiator.encrypt(&[0; 100]);
+        assert_eq!(
+            "7530d2a18720162ac09c25329a60d75adf36eda3c3",
+            hex::encode(initiator.encrypt(&[0x8e]))
+        );
+
+        // The second one sends its packet after 999 others, so both ciphers were rekeyed.
+        let mut responder = cipher(
+            false,
+            "6f312890ec83bbb26798abaadd574684a53e74ccef7953b790fcc29409080246",
+            "fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f0000000000000000000000000000000000000000000000000000000000000000",
+            "a8785af31c029efc82fa9fc677d7118031358d7c6a25b5779a9b900e5ccd94aac97eb36a3c5dbcdb2ca5843cc4c2fe0aaa46d10eb3d233a81c3dde476da00eef",
+        );
+        assert_eq!(
+            "b0490e26111cb2d55bbff2ace00f7f644f64006539abb4e7513f05107bb10608",
+            hex::encode(responder.session_id())
+        );
+        for _ in 0..999 {
+            responder.encrypt(&[]);
+        }
+        assert_eq!(
+            "d78adbcba0eebfb15cfbd8142c84dc729d233d0dc11b1d851e46a114122b8d5b96b7d59317",
+            hex::encode(
+                responder.encrypt(&hex::decode("3eb1d4e98035cfd8eeb29bac969ed3824a").unwrap())
+            )
+        );
+    }
+
+    #[test]
+    fn handshake_sides_agree_on_packets_and_garbage() {
+        let (initiator, responder) = (Handshake::new(MAGIC), Handshake::responder(MAGIC));
+        let (initiator_key, responder_key) = (initiator.key(), responder.key());
+
+        let (mut initiator, initiator_packets) =
+            initiator.complete(responder_key[..KEY_LEN].try_into().unwrap(), &[b"decoy"]);
+        let (mut responder, _) =
+            responder.complete(initiator_key[..KEY_LEN].try_into().unwrap(), &[]);
+        assert_eq!(initiator.session_id(), responder.session_id());
+
+        // The garbage of the initiator is followed by its terminator and version packet.
+        let mut buffer = BytesMut::from(&initiator_key[KEY_LEN..]);
+        buffer.extend_from_slice(&initiator_packets[..5]);
+        assert_eq!(None, responder.read_garbage(&mut buffer).unwrap());
+        buffer.extend_from_slice(&initiator_packets[5..]);
+        buffer.extend(initiator.encrypt(b"hello"));
+        let garbage_len = initiator_key.len() - KEY_LEN;
+        assert_eq!(
+            Some(garbage_len),
+            responder.read_garbage(&mut buffer).unwrap()
+        );
+
+        let decoy = responder.decrypt(&mut buffer).unwrap().unwrap();
+        assert!(decoy.decoy && decoy.contents == b"decoy");
+        let version = responder.decrypt(&mut buffer).unwrap().unwrap();
+        assert!(!version.decoy && version.contents.is_empty());
+        let last = buffer.split_off(buffer.len() - 1);
+        assert!(responder.decrypt(&mut buffer).unwrap().is_none());
+        buffer.extend_from_slice(&last);
+        assert_eq!(
+            b"hello".to_vec(),
+            responder.decrypt(&mut buffer).unwrap().unwrap().contents
+        );
+    }
+
+    #[test]
+    fn messages_roundtrip_with_short_and_long_ids() {
+        for payload in [NetworkMessage::Ping(42), NetworkMessage::Verack] {
+            let message = RawNetworkMessage {
+                magic: MAGIC,
+                payload,
+            };
+
+            let contents = encode_message(&message);
+
+            assert_eq!(message, decode_message(MAGIC, &contents).unwrap());
+        }
+        assert_eq!(
+            18,
+            encode_message(&RawNetworkMessage {
+                magic: MAGIC,
+                payload: NetworkMessage::Ping(42)
+            })[0]
+        );
+        assert!(decode_message(MAGIC, &[29]).is_err());
+    }
+}
@@ -0,0 +1,464 @@
+use std::io;
+
+use bytes::BytesMut;
+use rand::{thread_rng, Rng};
+use rlp::{PayloadInfo, Rlp, RlpStream};
+use secp256k1::{All, PublicKey, Secp256k1, SecretKey};
+
+use crate::p2p::{
+    protocol::{Protocol, Session},
+    view::{Event, EventDirection},
+    P2PError,
+};
+
+use self::rlpx::{FrameCodec, Secrets};
+
+mod rlpx;
+
+/// The client id announced in the hello message when no other one is configured.
+pub const DEFAULT_CLIENT_ID: &str = concat!("p2p-handshake/v", env!("CARGO_PKG_VERSION"));
+
+/// The devp2p base protocol version we speak.
+const P2P_VERSION: u8 = 5;
+
+/// The capabilities announced in our hello message, so peers do not consider us useless.
+const CAPABILITIES: [(&str, u8); 2] = [("eth", 68), ("snap", 1)];
+
+const HELLO_MESSAGE_ID: u8 = 0x00;
+const DISCONNECT_MESSAGE_ID: u8 = 0x01;
+
+/// The Ethereum devp2p handshake: the RLPx auth/ack exchange followed by the hello messages.
+pub struct Eth {
+    node_addr: String,
+    remote_id: PublicKey,
+    client_id: String,
+    secp: Secp256k1<All>,
+    key: SecretKey,
+    ephemeral_key: SecretKey,
+    nonce: [u8; 32],
+    stage: Stage,
+}
+
+enum Stage {
+    /// Not connected yet.
+    Auth,
+    /// Waiting for the ack of our auth message.
+    Ack {
+        auth: Vec<u8>,
+    },
+    /// Waiting for the peer hello, once ours was sent.
+    Hello {
+        codec: Box<FrameCodec>,
+    },
+    Complete,
+}
+
+impl Eth {
+    /// Prepares the handshake with the node of the `enode://<node id>@<host>:<port>` URL.
+    /// A new node key is used for every handshake.
+    pub fn new(enode: &str, client_id: String) -> Result<Eth, P2PError> {
+        let (remote_id, node_addr) = parse_enode(enode)?;
+        Ok(Eth {
+            node_addr,
+            remote_id,
+            client_id,
+            secp: Secp256k1::new(),
+            key: SecretKey::new(&mut thread_rng()),
+            ephemeral_key: SecretKey::new(&mut thread_rng()),
+            nonce: thread_rng().gen(),
+            stage: Stage::Auth,
+        })
+    }
+
+    /// Overrides the configuration with the options of a single target, like
+    /// `client_id=Geth/v1.13.0`.
+    pub fn apply_options(
+        &mut self,
+        target: &str,
+        options: &[(String, String)],
+    ) -> Result<(), P2PError> {
+        for (key, val) in options {
+            match key.as_str() {
+                "client_id" => self.client_id = val.to_owned(),
+                _ => {
+                    return Err(P2PError::InvalidTarget {
+                        target: target.to_string(),
+                        reason: format!("unknown option {}", key),
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the ack message, answering it with our hello once the secrets are agreed.
+    fn handle_ack(
+        &mut self,
+        auth: &[u8],
+        ack: &[u8],
+        session: &mut Session,
+    ) -> Result<FrameCodec, P2PError> {
+        let remote = rlpx::read_ack(&self.key, ack)?;
+        session.publish(Event::new("ack".to_string(), EventDirection::IN))?;
+
+        let mut codec = FrameCodec::new(Secrets::derive(
+            &self.ephemeral_key,
+            &self.nonce,
+            &remote.recipient_ephemeral,
+            &remote.recipient_nonce,
+            auth,
+            ack,
+            true,
+        ));
+        let hello = hello_message(
+            &self.client_id,
+            &rlpx::node_id(&PublicKey::from_secret_key(&self.secp, &self.key)),
+        );
+        let event = Event::new("hello".to_string(), EventDirection::OUT);
+        session.send(event, codec.encode(&hello))?;
+        Ok(codec)
+    }
+
+    /// Handles a message received before the peer hello, which can only be the hello
+    /// itself or a disconnection.
+    fn handle_message(&mut self, frame: &[u8], session: &mut Session) -> Result<(), P2PError> {
+        let id_len = PayloadInfo::from(frame)?.total();
+        let (id, payload) = match (frame.get(..id_len), frame.get(id_len..)) {
+            (Some(id), Some(payload)) => (id, payload),
+            _ => return Err(P2PError::Decode("truncated message".into())),
+        };
+        let id: u8 = Rlp::new(id).as_val()?;
+        let payload = Rlp::new(payload);
+        match id {
+            HELLO_MESSAGE_ID => {
+                let mut event = Event::new("hello".to_string(), EventDirection::IN);
+                event.set_pair("vers".to_string(), payload.val_at::<u64>(0)?);
+                event.set_pair("client-id".to_string(), payload.val_at::<String>(1)?);
+                let capabilities = payload
+                    .at(2)?
+                    .iter()
+                    .map(|cap| {
+                        Ok(format!(
+                            "{}/{}",
+                            cap.val_at::<String>(0)?,
+                            cap.val_at::<u64>(1)?
+                        ))
+                    })
+                    .collect::<Result<Vec<String>, rlp::DecoderError>>()?;
+                event.set_pair("caps".to_string(), capabilities);
+                session.publish(event)?;
+                self.stage = Stage::Complete;
+                Ok(())
+            }
+            DISCONNECT_MESSAGE_ID => {
+                let mut event = Event::new("disconnect".to_string(), EventDirection::IN);
+                // Some clients send the reason alone, instead of in a list.
+                let reason: u8 = match payload.is_list() {
+                    true => payload.val_at(0)?,
+                    false => payload.as_val()?,
+                };
+                event.set_pair("reason".to_string(), disconnect_reason(reason).to_string());
+                session.publish(event)?;
+                Err(P2PError::PeerClosed(Some(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    format!("disconnected: {}", disconnect_reason(reason)),
+                ))))
+            }
+            _ => Err(P2PError::ProtocolViolation(format!(
+                "message {:#04x} received before hello",
+                id
+            ))),
+        }
+    }
+}
+
+impl Protocol for Eth {
+    fn default_port(&self) -> u16 {
+        30303
+    }
+
+    fn node_addr<'a>(&'a self, _target: &'a str) -> &'a str {
+        &self.node_addr
+    }
+
+    fn start(&mut self, session: &mut Session) -> Result<(), P2PError> {
+        let auth = rlpx::auth(
+            &self.secp,
+            &self.key,
+            &self.ephemeral_key,
+            &self.nonce,
+            &self.remote_id,
+        );
+        let event = Event::new("auth".to_string(), EventDirection::OUT);
+        session.send(event, auth.clone())?;
+        self.stage = Stage::Ack { auth };
+        Ok(())
+    }
+
+    fn on_data(&mut self, buffer: &mut BytesMut, session: &mut Session) -> Result<(), P2PError> {
+        loop {
+            match &mut self.stage {
+                Stage::Ack { auth } => {
+                    let ack_len = match rlpx::packet_len(buffer) {
+                        Some(ack_len) if buffer.len() >= ack_len => ack_len,
+                        _ => return Ok(()),
+                    };
+                    let auth = std::mem::take(auth);
+                    let ack = buffer.split_to(ack_len);
+                    let codec = self.handle_ack(&auth, &ack, session)?;
+                    self.stage = Stage::Hello {
+                        codec: Box::new(codec),
+                    };
+                }
+                Stage::Hello { codec } => match codec.decode(buffer)? {
+                    Some(frame) => self.handle_message(&frame, session)?,
+                    None => return Ok(()),
+                },
+                Stage::Auth | Stage::Complete => return Ok(()),
+            }
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        matches!(self.stage, Stage::Complete)
+    }
+}
+
+/// Splits an `enode://<node id>@<host>:<port>` URL in the node public key and its address.
+fn parse_enode(enode: &str) -> Result<(PublicKey, String), P2PError> {
+    let invalid_enode = |reason: &str| P2PError::InvalidTarget {
+        target: enode.to_string(),
+        reason: reason.to_string(),
+    };
+    let (node_id, node_addr) = enode
+        .strip_prefix("enode://")
+        .and_then(|enode| enode.split_once('@'))
+        .ok_or_else(|| invalid_enode("not like enode://<node id>@<host>:<port>"))?;
+    // The discovery port is not needed for the handshake.
+    let node_addr = node_addr.split('?').next().unwrap_or_default();
+    let remote_id = hex::decode(node_id)
+        .ok()
+        .and_then(|node_id| rlpx::public_key(&node_id).ok())
+        .ok_or_else(|| invalid_enode("the node id is not a valid public key"))?;
+    Ok((remote_id, node_addr.to_string()))
+}
+
+/// The hello message frame data, including its message id.
+fn hello_message(client_id: &str, node_id: &[u8]) -> Vec<u8> {
+    let mut hello = RlpStream::new_list(5);
+    hello.append(&P2P_VERSION).append(&client_id);
+    hello.begin_list(CAPABILITIES.len());
+    for (name, version) in CAPABILITIES {
+        hello.begin_list(2).append(&name).append(&version);
+    }
+    // We do not listen for connections.
+    hello.append(&0u16).append(&node_id.to_vec());
+
+    let mut frame = rlp::encode(&HELLO_MESSAGE_ID).to_vec();
+    frame.extend(hello.out());
+    frame
+}
+
+fn disconnect_reason(reason: u8) -> &'static str {
+    match reason {
+        0x00 => "disconnect requested",
+        0x01 => "TCP sub-system error",
+        0x02 => "breach of protocol",
+        0x03 => "useless peer",
+        0x04 => "too many peers",
+        0x05 => "already connected",
+        0x06 => "incompatible P2P protocol version",
+        0x07 => "null node identity received",
+        0x08 => "client quitting",
+        0x09 => "unexpected identity",
+        0x0a => "connected to self",
+        0x0b => "ping timeout",
+        0x10 => "subprotocol specific reason",
+        _ => "unknown reason",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::p2p::protocol::{
+        run,
+        tests::{peer, TIMEOUTS},
+    };
+
+    async fn read_packet(stream: &mut TcpStream) -> Vec<u8> {
+        let mut packet = vec![0; 2];
+        stream.read_exact(&mut packet).await.unwrap();
+        packet.resize(rlpx::packet_len(&packet).unwrap(), 0);
+        stream.read_exact(&mut packet[2..]).await.unwrap();
+        packet
+    }
+
+    async fn read_frame(stream: &mut TcpStream, codec: &mut FrameCodec) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        loop {
+            if let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                return frame;
+            }
+            assert_ne!(0, stream.read_buf(&mut buffer).await.unwrap());
+        }
+    }
+
+    /// Starts a node that answers the RLPx handshake with the provided key, answering
+    /// the hello with the provided frame. It returns the enode URL of the node and the
+    /// hello message it received.
+    async fn responder(
+        key: SecretKey,
+        answer: Vec<u8>,
+    ) -> (String, tokio::task::JoinHandle<Vec<u8>>) {
+        let secp = Secp256k1::new();
+        let node_id = rlpx::node_id(&PublicKey::from_secret_key(&secp, &key));
+        let (addr, handle) = peer(move |mut stream| async move {
+            let auth_packet = read_packet(&mut stream).await;
+            let auth = rlpx::read_auth(&secp, &key, &auth_packet).unwrap();
+
+            let ephemeral_key = SecretKey::new(&mut thread_rng());
+            let nonce = thread_rng().gen();
+            let ack_packet = rlpx::ack(&secp, &ephemeral_key, &nonce, &auth.initiator_id);
+            stream.write_all(&ack_packet).await.unwrap();
+
+            let mut codec = FrameCodec::new(Secrets::derive(
+                &ephemeral_key,
+                &nonce,
+                &auth.initiator_ephemeral,
+                &auth.initiator_nonce,
+                &ack_packet,
+                &auth_packet,
+                false,
+            ));
+            stream.write_all(&codec.encode(&answer)).await.unwrap();
+            read_frame(&mut stream, &mut codec).await
+        })
+        .await;
+        let enode = format!("enode://{}@{}", hex::encode(node_id), addr);
+        (enode, handle)
+    }
+
+    #[tokio::test]
+    async fn eth_handshakes_with_rlpx_node() {
+        let key = SecretKey::new(&mut thread_rng());
+        let hello = hello_message("Geth/v1.13.0-stable/linux-amd64/go1.21.1", &[1; 64]);
+        let (enode, responder) = responder(key, hello).await;
+
+        let eth = Eth::new(&enode, "p2p-handshake/test".to_string()).unwrap();
+        let result = run(enode.clone(), TIMEOUTS, eth).await;
+
+        let event_chain = result.result().unwrap();
+        assert!(event_chain.is_complete());
+        let events: Vec<(&str, String)> = event_chain
+            .events()
+            .iter()
+            .filter(|ev| !matches!(ev.direction(), EventDirection::INTERNAL))
+            .map(|ev| (ev.name(), ev.direction().to_string()))
+            .collect();
+        let (out, r#in) = (
+            EventDirection::OUT.to_string(),
+            EventDirection::IN.to_string(),
+        );
+        assert_eq!(
+            vec![("auth", out.clone()), ("ack", r#in.clone())],
+            events[..2]
+        );
+        // Both hello messages are sent at the same time, so they can be recorded in any order.
+        assert_eq!(2, events[2..].len());
+        assert!(events[2..].contains(&("hello", out)));
+        assert!(events[2..].contains(&("hello", r#in)));
+
+        let peer_hello = event_chain
+            .events()
+            .iter()
+            .find(|ev| ev.name() == "hello" && matches!(ev.direction(), EventDirection::IN))
+            .unwrap();
+        assert_eq!(
+            &[
+                ("vers".to_string(), 5u64.into()),
+                (
+                    "client-id".to_string(),
+                    "Geth/v1.13.0-stable/linux-amd64/go1.21.1".into()
+                ),
+                (
+                    "caps".to_string(),
+                    ["eth/68", "snap/1"].map(String::from).to_vec().into()
+                ),
+            ],
+            peer_hello.data_pairs()
+        );
+
+        let our_hello = responder.await.unwrap();
+        let our_hello = Rlp::new(&our_hello[1..]);
+        assert_eq!("p2p-handshake/test", our_hello.val_at::<String>(1).unwrap());
+    }
+
+    #[tokio::test]
+    async fn eth_reports_disconnections() {
+        let key = SecretKey::new(&mut thread_rng());
+        let mut disconnect = rlp::encode(&DISCONNECT_MESSAGE_ID).to_vec();
+        disconnect.extend(rlp::encode_list(&[0x04u8]));
+        let (enode, _) = responder(key, disconnect).await;
+
+        let eth = Eth::new(&enode, DEFAULT_CLIENT_ID.to_string()).unwrap();
+        let result = run(enode, TIMEOUTS, eth).await;
+
+        assert!(!result.event_chain().is_complete());
+        assert_eq!(
+            "P2P error: connection closed by peer: disconnected: too many peers",
+            result.error().unwrap().to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn eth_rejects_truncated_messages() {
+        let key = SecretKey::new(&mut thread_rng());
+        // The header announces a four bytes message id that is not there.
+        let (enode, _) = responder(key, vec![0x83]).await;
+
+        let eth = Eth::new(&enode, DEFAULT_CLIENT_ID.to_string()).unwrap();
+        let result = run(enode, TIMEOUTS, eth).await;
+
+        assert!(!result.event_chain().is_complete());
+        assert!(
+            matches!(result.error(), Some(P2PError::Decode(_))),
+            "{:?}",
+            result.error()
+        );
+    }
+
+    #[test]
+    fn parse_enode_splits_node_id_and_address() {
+        let node_id = "a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c";
+        let (remote_id, node_addr) = parse_enode(&format!(
+            "enode://{}@52.16.188.185:30303?discport=30301",
+            node_id
+        ))
+        .unwrap();
+
+        assert_eq!(node_id, hex::encode(rlpx::node_id(&remote_id)));
+        assert_eq!("52.16.188.185:30303", node_addr);
+    }
+
+    #[test]
+    fn parse_enode_rejects_invalid_urls() {
+        for enode in [
+            "52.16.188.185:30303",
+            "enode://52.16.188.185:30303",
+            "enode://abcd@52.16.188.185:30303",
+            "enode://zz@52.16.188.185:30303",
+        ] {
+            assert!(
+                matches!(parse_enode(enode), Err(P2PError::InvalidTarget { .. })),
+                "{}",
+                enode
+            );
+        }
+    }
+}
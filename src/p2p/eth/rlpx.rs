@@ -0,0 +1,505 @@
+// The RLPx transport: the ECIES encrypted auth/ack exchange and the framing of the
+// messages sent after it. See <https://github.com/ethereum/devp2p/blob/master/rlpx.md>.
+//
+// Both sides of the exchange are implemented, although the recipient one is only used by tests.
+
+use aes::{
+    cipher::{BlockEncrypt, KeyInit, KeyIvInit, StreamCipher},
+    Aes128, Aes256,
+};
+use bytes::{Buf, BytesMut};
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, Rng};
+use rlp::{Rlp, RlpStream};
+use secp256k1::{ecdh::shared_secret_point, All, Message, PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+use sha3::Keccak256;
+
+use crate::p2p::P2PError;
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+/// The auth and ack versions we speak, as of EIP-8.
+const HANDSHAKE_VERSION: u8 = 4;
+
+/// The bytes ECIES adds to the plain text: the ephemeral public key, the IV and the MAC.
+const ECIES_OVERHEAD: usize = 65 + 16 + 32;
+
+/// The data of the auth message, as seen by the recipient.
+#[cfg(test)]
+pub struct Auth {
+    pub initiator_id: PublicKey,
+    pub initiator_ephemeral: PublicKey,
+    pub initiator_nonce: [u8; 32],
+}
+
+/// The data of the ack message, as seen by the initiator.
+pub struct Ack {
+    pub recipient_ephemeral: PublicKey,
+    pub recipient_nonce: [u8; 32],
+}
+
+/// Builds the auth message the initiator starts the handshake with.
+pub fn auth(
+    secp: &Secp256k1<All>,
+    key: &SecretKey,
+    ephemeral_key: &SecretKey,
+    nonce: &[u8; 32],
+    remote_id: &PublicKey,
+) -> Vec<u8> {
+    // The signature proves the ownership of the ephemeral key, which the recipient recovers from it.
+    let static_shared = ecdh(remote_id, key);
+    let signed = Message::from_slice(&xor(&static_shared, nonce)).expect("32 bytes message");
+    let (recovery_id, signature) = secp
+        .sign_ecdsa_recoverable(&signed, ephemeral_key)
+        .serialize_compact();
+    let mut signature = signature.to_vec();
+    signature.push(recovery_id.to_i32() as u8);
+
+    let mut body = RlpStream::new_list(4);
+    body.append(&signature)
+        .append(&node_id(&PublicKey::from_secret_key(secp, key)))
+        .append(&nonce.to_vec())
+        .append(&HANDSHAKE_VERSION);
+    seal(secp, remote_id, body)
+}
+
+/// Reads the auth message with the recipient key.
+#[cfg(test)]
+pub fn read_auth(secp: &Secp256k1<All>, key: &SecretKey, packet: &[u8]) -> Result<Auth, P2PError> {
+    let body = open(key, packet)?;
+    let body = Rlp::new(&body);
+    let signature: Vec<u8> = body.val_at(0)?;
+    let initiator_id = public_key(&body.val_at::<Vec<u8>>(1)?)?;
+    let initiator_nonce = nonce(&body.val_at::<Vec<u8>>(2)?)?;
+
+    if signature.len() != 65 {
+        return Err(P2PError::Decode("invalid auth signature length".into()));
+    }
+    let static_shared = ecdh(&initiator_id, key);
+    let signed =
+        Message::from_slice(&xor(&static_shared, &initiator_nonce)).expect("32 bytes message");
+    let recovery_id =
+        secp256k1::ecdsa::RecoveryId::from_i32(signature[64] as i32).map_err(secp_error)?;
+    let signature =
+        secp256k1::ecdsa::RecoverableSignature::from_compact(&signature[..64], recovery_id)
+            .map_err(secp_error)?;
+    let initiator_ephemeral = secp
+        .recover_ecdsa(&signed, &signature)
+        .map_err(secp_error)?;
+    Ok(Auth {
+        initiator_id,
+        initiator_ephemeral,
+        initiator_nonce,
+    })
+}
+
+/// Builds the ack message the recipient answers the auth one with.
+#[cfg(test)]
+pub fn ack(
+    secp: &Secp256k1<All>,
+    ephemeral_key: &SecretKey,
+    nonce: &[u8; 32],
+    remote_id: &PublicKey,
+) -> Vec<u8> {
+    let mut body = RlpStream::new_list(3);
+    body.append(&node_id(&PublicKey::from_secret_key(secp, ephemeral_key)))
+        .append(&nonce.to_vec())
+        .append(&HANDSHAKE_VERSION);
+    seal(secp, remote_id, body)
+}
+
+/// Reads the ack message with the initiator key.
+pub fn read_ack(key: &SecretKey, packet: &[u8]) -> Result<Ack, P2PError> {
+    let body = open(key, packet)?;
+    let body = Rlp::new(&body);
+    Ok(Ack {
+        recipient_ephemeral: public_key(&body.val_at::<Vec<u8>>(0)?)?,
+        recipient_nonce: nonce(&body.val_at::<Vec<u8>>(1)?)?,
+    })
+}
+
+/// The length of the auth or ack message at the start of the buffer, if its
+/// size prefix was already received.
+pub fn packet_len(buffer: &[u8]) -> Option<usize> {
+    (buffer.len() >= 2).then(|| 2 + u16::from_be_bytes([buffer[0], buffer[1]]) as usize)
+}
+
+/// Pads and encrypts the message body for the remote, prefixing it with its size.
+fn seal(secp: &Secp256k1<All>, remote_id: &PublicKey, body: RlpStream) -> Vec<u8> {
+    let mut plain = body.out().to_vec();
+    // Padding makes the messages distinguishable from the pre EIP-8 ones.
+    plain.resize(plain.len() + thread_rng().gen_range(100..200), 0);
+    let size = ((plain.len() + ECIES_OVERHEAD) as u16).to_be_bytes();
+    let mut packet = size.to_vec();
+    packet.extend(ecies_encrypt(secp, remote_id, &plain, &size));
+    packet
+}
+
+/// Decrypts a size prefixed message.
+fn open(key: &SecretKey, packet: &[u8]) -> Result<Vec<u8>, P2PError> {
+    let (size, encrypted) = packet.split_at(2);
+    ecies_decrypt(key, encrypted, size)
+}
+
+fn ecies_encrypt(
+    secp: &Secp256k1<All>,
+    remote: &PublicKey,
+    plain: &[u8],
+    shared_mac_data: &[u8],
+) -> Vec<u8> {
+    let ephemeral_key = SecretKey::new(&mut thread_rng());
+    let (enc_key, mac_key) = ecies_keys(&ecdh(remote, &ephemeral_key));
+    let iv: [u8; 16] = thread_rng().gen();
+
+    let mut encrypted = plain.to_vec();
+    Aes128Ctr::new(&enc_key.into(), &iv.into()).apply_keystream(&mut encrypted);
+    let mac = ecies_mac(&mac_key, &iv, &encrypted, shared_mac_data);
+
+    let mut message = PublicKey::from_secret_key(secp, &ephemeral_key)
+        .serialize_uncompressed()
+        .to_vec();
+    message.extend(iv);
+    message.extend(encrypted);
+    message.extend(mac.finalize().into_bytes());
+    message
+}
+
+fn ecies_decrypt(
+    key: &SecretKey,
+    message: &[u8],
+    shared_mac_data: &[u8],
+) -> Result<Vec<u8>, P2PError> {
+    if message.len() < ECIES_OVERHEAD {
+        return Err(P2PError::Decode("encrypted message too short".into()));
+    }
+    let remote = PublicKey::from_slice(&message[..65]).map_err(secp_error)?;
+    let iv: [u8; 16] = message[65..81].try_into().expect("16 bytes IV");
+    let (encrypted, tag) = message[81..].split_at(message.len() - 81 - 32);
+
+    let (enc_key, mac_key) = ecies_keys(&ecdh(&remote, key));
+    ecies_mac(&mac_key, &iv, encrypted, shared_mac_data)
+        .verify_slice(tag)
+        .map_err(|_| P2PError::Decode("invalid encrypted message MAC".into()))?;
+
+    let mut plain = encrypted.to_vec();
+    Aes128Ctr::new(&enc_key.into(), &iv.into()).apply_keystream(&mut plain);
+    Ok(plain)
+}
+
+/// Derives the encryption and MAC keys from the ECDH shared secret, with the NIST SP 800-56
+/// concatenation KDF. A single SHA-256 round is enough for the 32 bytes we need.
+fn ecies_keys(shared: &[u8; 32]) -> ([u8; 16], [u8; 32]) {
+    let derived = Sha256::new()
+        .chain_update(1u32.to_be_bytes())
+        .chain_update(shared)
+        .finalize();
+    let enc_key = derived[..16].try_into().expect("16 bytes key");
+    let mac_key = Sha256::digest(&derived[16..]).into();
+    (enc_key, mac_key)
+}
+
+fn ecies_mac(
+    mac_key: &[u8; 32],
+    iv: &[u8],
+    encrypted: &[u8],
+    shared_mac_data: &[u8],
+) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key).expect("any key size is valid");
+    mac.update(iv);
+    mac.update(encrypted);
+    mac.update(shared_mac_data);
+    mac
+}
+
+/// The secrets both sides agree on after the auth/ack exchange.
+pub struct Secrets {
+    aes: [u8; 32],
+    mac: [u8; 32],
+    egress_mac: Keccak256,
+    ingress_mac: Keccak256,
+}
+
+impl Secrets {
+    /// Derives the secrets from the local ephemeral key and nonce, the remote ones and
+    /// the auth and ack messages, as sent and received.
+    pub fn derive(
+        ephemeral_key: &SecretKey,
+        nonce: &[u8; 32],
+        remote_ephemeral: &PublicKey,
+        remote_nonce: &[u8; 32],
+        sent: &[u8],
+        received: &[u8],
+        initiator: bool,
+    ) -> Secrets {
+        let ephemeral_shared = ecdh(remote_ephemeral, ephemeral_key);
+        let (initiator_nonce, recipient_nonce) = match initiator {
+            true => (nonce, remote_nonce),
+            false => (remote_nonce, nonce),
+        };
+        let nonces = keccak(&[recipient_nonce, initiator_nonce]);
+        let shared = keccak(&[&ephemeral_shared, &nonces]);
+        let aes = keccak(&[&ephemeral_shared, &shared]);
+        let mac = keccak(&[&ephemeral_shared, &aes]);
+        Secrets {
+            aes,
+            mac,
+            egress_mac: Keccak256::new()
+                .chain_update(xor(&mac, remote_nonce))
+                .chain_update(sent),
+            ingress_mac: Keccak256::new()
+                .chain_update(xor(&mac, nonce))
+                .chain_update(received),
+        }
+    }
+}
+
+/// Encrypts and authenticates the frames sent after the handshake, and
+/// verifies and decrypts the received ones.
+pub struct FrameCodec {
+    egress_aes: Aes256Ctr,
+    ingress_aes: Aes256Ctr,
+    mac_cipher: Aes256,
+    egress_mac: Keccak256,
+    ingress_mac: Keccak256,
+    // The size of the frame being received, once its header was read.
+    ingress_frame_size: Option<usize>,
+}
+
+impl FrameCodec {
+    pub fn new(secrets: Secrets) -> FrameCodec {
+        FrameCodec {
+            egress_aes: Aes256Ctr::new(&secrets.aes.into(), &[0; 16].into()),
+            ingress_aes: Aes256Ctr::new(&secrets.aes.into(), &[0; 16].into()),
+            mac_cipher: Aes256::new(&secrets.mac.into()),
+            egress_mac: secrets.egress_mac,
+            ingress_mac: secrets.ingress_mac,
+            ingress_frame_size: None,
+        }
+    }
+
+    /// Builds the frame that carries the provided data.
+    pub fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut header = [0; 16];
+        header[..3].copy_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+        // The header data, capability and context ids, is always zero.
+        header[3..6].copy_from_slice(&[0xc2, 0x80, 0x80]);
+        self.egress_aes.apply_keystream(&mut header);
+        let header_mac = update_mac(&mut self.egress_mac, &self.mac_cipher, &header);
+
+        let mut frame = data.to_vec();
+        frame.resize(padded_len(data.len()), 0);
+        self.egress_aes.apply_keystream(&mut frame);
+        self.egress_mac.update(&frame);
+        let seed = digest(&self.egress_mac);
+        let frame_mac = update_mac(&mut self.egress_mac, &self.mac_cipher, &seed);
+
+        [&header[..], &header_mac, &frame, &frame_mac].concat()
+    }
+
+    /// Reads the data of the frame at the start of the buffer, if it was completely received.
+    pub fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Vec<u8>>, P2PError> {
+        let frame_size = match self.ingress_frame_size {
+            Some(frame_size) => frame_size,
+            None => {
+                if buffer.len() < 32 {
+                    return Ok(None);
+                }
+                let mut header: [u8; 16] = buffer[..16].try_into().expect("16 bytes header");
+                if update_mac(&mut self.ingress_mac, &self.mac_cipher, &header) != buffer[16..32] {
+                    return Err(P2PError::Decode("invalid frame header MAC".into()));
+                }
+                self.ingress_aes.apply_keystream(&mut header);
+                buffer.advance(32);
+                let frame_size = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+                self.ingress_frame_size = Some(frame_size);
+                frame_size
+            }
+        };
+
+        let padded_size = padded_len(frame_size);
+        if buffer.len() < padded_size + 16 {
+            return Ok(None);
+        }
+        let mut frame = buffer.split_to(padded_size).to_vec();
+        let frame_mac = buffer.split_to(16);
+        self.ingress_mac.update(&frame);
+        let seed = digest(&self.ingress_mac);
+        if update_mac(&mut self.ingress_mac, &self.mac_cipher, &seed) != frame_mac[..] {
+            return Err(P2PError::Decode("invalid frame MAC".into()));
+        }
+        self.ingress_aes.apply_keystream(&mut frame);
+        frame.truncate(frame_size);
+        self.ingress_frame_size = None;
+        Ok(Some(frame))
+    }
+}
+
+/// Updates the MAC state with its encrypted digest xored with the seed, returning the new digest.
+fn update_mac(mac: &mut Keccak256, cipher: &Aes256, seed: &[u8; 16]) -> [u8; 16] {
+    let mut block = digest(mac).into();
+    cipher.encrypt_block(&mut block);
+    mac.update(xor(&block.into(), seed));
+    digest(mac)
+}
+
+/// The first 16 bytes of the running MAC digest.
+fn digest(mac: &Keccak256) -> [u8; 16] {
+    mac.clone().finalize()[..16]
+        .try_into()
+        .expect("16 bytes digest")
+}
+
+fn padded_len(len: usize) -> usize {
+    len.div_ceil(16) * 16
+}
+
+/// The x coordinate of the ECDH shared point.
+fn ecdh(public_key: &PublicKey, secret_key: &SecretKey) -> [u8; 32] {
+    shared_secret_point(public_key, secret_key)[..32]
+        .try_into()
+        .expect("32 bytes coordinate")
+}
+
+fn keccak(parts: &[&[u8; 32]]) -> [u8; 32] {
+    parts
+        .iter()
+        .fold(Keccak256::new(), |hasher, part| hasher.chain_update(part))
+        .finalize()
+        .into()
+}
+
+fn xor<const N: usize>(a: &[u8; N], b: &[u8; N]) -> [u8; N] {
+    let mut xored = *a;
+    xored.iter_mut().zip(b).for_each(|(x, b)| *x ^= b);
+    xored
+}
+
+/// The 64 bytes node id of a public key, its uncompressed form without the prefix.
+pub fn node_id(public_key: &PublicKey) -> Vec<u8> {
+    public_key.serialize_uncompressed()[1..].to_vec()
+}
+
+/// Parses the public key of a 64 bytes node id.
+pub fn public_key(node_id: &[u8]) -> Result<PublicKey, P2PError> {
+    if node_id.len() != 64 {
+        return Err(P2PError::Decode("invalid node id length".into()));
+    }
+    PublicKey::from_slice(&[&[0x04], node_id].concat()).map_err(secp_error)
+}
+
+fn nonce(nonce: &[u8]) -> Result<[u8; 32], P2PError> {
+    nonce
+        .try_into()
+        .map_err(|_| P2PError::Decode("invalid nonce length".into()))
+}
+
+fn secp_error(err: secp256k1::Error) -> P2PError {
+    P2PError::Decode(Box::new(err))
+}
+
+impl From<rlp::DecoderError> for P2PError {
+    fn from(err: rlp::DecoderError) -> Self {
+        P2PError::Decode(Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ecies_decrypts_what_it_encrypts() {
+        let secp = Secp256k1::new();
+        let key = SecretKey::new(&mut thread_rng());
+
+        let encrypted = ecies_encrypt(
+            &secp,
+            &PublicKey::from_secret_key(&secp, &key),
+            b"hello",
+            b"mac data",
+        );
+
+        assert_eq!(
+            b"hello".to_vec(),
+            ecies_decrypt(&key, &encrypted, b"mac data").unwrap()
+        );
+        assert!(ecies_decrypt(&key, &encrypted, b"other mac data").is_err());
+    }
+
+    #[test]
+    fn both_sides_agree_on_the_handshake_secrets() {
+        let secp = Secp256k1::new();
+        let (initiator_key, initiator_ephemeral) = (
+            SecretKey::new(&mut thread_rng()),
+            SecretKey::new(&mut thread_rng()),
+        );
+        let (recipient_key, recipient_ephemeral) = (
+            SecretKey::new(&mut thread_rng()),
+            SecretKey::new(&mut thread_rng()),
+        );
+        let (initiator_nonce, recipient_nonce) = (thread_rng().gen(), thread_rng().gen());
+
+        let auth_packet = auth(
+            &secp,
+            &initiator_key,
+            &initiator_ephemeral,
+            &initiator_nonce,
+            &PublicKey::from_secret_key(&secp, &recipient_key),
+        );
+        let auth = read_auth(&secp, &recipient_key, &auth_packet).unwrap();
+        assert_eq!(
+            PublicKey::from_secret_key(&secp, &initiator_key),
+            auth.initiator_id
+        );
+        assert_eq!(
+            PublicKey::from_secret_key(&secp, &initiator_ephemeral),
+            auth.initiator_ephemeral
+        );
+        assert_eq!(Some(auth_packet.len()), packet_len(&auth_packet));
+
+        let ack_packet = ack(
+            &secp,
+            &recipient_ephemeral,
+            &recipient_nonce,
+            &auth.initiator_id,
+        );
+        let ack = read_ack(&initiator_key, &ack_packet).unwrap();
+        assert_eq!(recipient_nonce, ack.recipient_nonce);
+
+        let mut initiator = FrameCodec::new(Secrets::derive(
+            &initiator_ephemeral,
+            &initiator_nonce,
+            &ack.recipient_ephemeral,
+            &ack.recipient_nonce,
+            &auth_packet,
+            &ack_packet,
+            true,
+        ));
+        let mut recipient = FrameCodec::new(Secrets::derive(
+            &recipient_ephemeral,
+            &recipient_nonce,
+            &auth.initiator_ephemeral,
+            &auth.initiator_nonce,
+            &ack_packet,
+            &auth_packet,
+            false,
+        ));
+
+        for data in [&b"first frame"[..], &[7; 40]] {
+            let mut buffer = BytesMut::from(&initiator.encode(data)[..]);
+            let last = buffer.split_off(buffer.len() - 1);
+            // Frames are only read once completely received.
+            assert_eq!(None, recipient.decode(&mut buffer).unwrap());
+            buffer.extend(last);
+            assert_eq!(Some(data.to_vec()), recipient.decode(&mut buffer).unwrap());
+            assert!(buffer.is_empty());
+        }
+        let mut buffer = BytesMut::from(&recipient.encode(b"answer")[..]);
+        assert_eq!(
+            Some(b"answer".to_vec()),
+            initiator.decode(&mut buffer).unwrap()
+        );
+    }
+}
@@ -0,0 +1,694 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use bytes::BytesMut;
+use snow::{HandshakeState, TransportState};
+
+use crate::p2p::{
+    protocol::{Protocol, Session},
+    view::{Event, EventDirection},
+    P2PError,
+};
+
+use self::{
+    noise::{Identity, NOISE_PARAMS},
+    wire::{Value, MULTISTREAM, YAMUX_DATA, YAMUX_GO_AWAY, YAMUX_RST, YAMUX_SYN},
+};
+
+mod noise;
+mod wire;
+
+const NOISE: &str = "/noise";
+const YAMUX: &str = "/yamux/1.0.0";
+const IDENTIFY: &str = "/ipfs/id/1.0.0";
+
+/// The yamux stream we open for the identify protocol. Dialers use odd stream ids.
+const IDENTIFY_STREAM_ID: u32 = 1;
+
+/// The longest identify message we accept, as the go and rust implementations do.
+const MAX_IDENTIFY_LEN: usize = 4096;
+
+/// The libp2p connection upgrade: the Noise security and the yamux muxer are negotiated
+/// with multistream-select, optionally followed by the identify protocol.
+pub struct Libp2p {
+    node_addr: String,
+    peer_id: Option<String>,
+    identify: bool,
+    identity: Identity,
+    /// The Noise session once the connection is secured.
+    transport: Option<Box<TransportState>>,
+    /// The decrypted data not processed yet.
+    plaintext: BytesMut,
+    stage: Stage,
+}
+
+enum Stage {
+    /// Not connected yet.
+    Connect,
+    /// Negotiating the security protocol.
+    Security(Negotiation),
+    /// Waiting for the Noise message of the peer, once ours was sent.
+    Noise {
+        handshake: Box<HandshakeState>,
+        payload: Vec<u8>,
+    },
+    /// Negotiating the muxer over the secured connection.
+    Muxer(Negotiation),
+    /// Waiting for the identify message on its stream.
+    Identify {
+        stream: BytesMut,
+        negotiation: Negotiation,
+    },
+    Complete,
+}
+
+/// A multistream-select negotiation of a single protocol, as the dialer.
+struct Negotiation {
+    protocol: &'static str,
+    header_received: bool,
+    accepted: bool,
+}
+
+impl Negotiation {
+    fn new(protocol: &'static str) -> Negotiation {
+        Negotiation {
+            protocol,
+            header_received: false,
+            accepted: false,
+        }
+    }
+
+    /// Reads the peer answers to our proposal, returning whether the protocol was
+    /// accepted or more data is needed.
+    fn on_data(&mut self, buffer: &mut BytesMut, session: &mut Session) -> Result<bool, P2PError> {
+        if self.accepted {
+            return Ok(true);
+        }
+        while let Some(message) = wire::read_multistream(buffer)? {
+            if !self.header_received {
+                if message != MULTISTREAM {
+                    return Err(P2PError::ProtocolViolation(format!(
+                        "unexpected multistream-select header {}",
+                        message
+                    )));
+                }
+                self.header_received = true;
+                continue;
+            }
+            if message == "na" {
+                return Err(P2PError::ProtocolViolation(format!(
+                    "{} not supported by the peer",
+                    self.protocol
+                )));
+            }
+            if message != self.protocol {
+                return Err(P2PError::ProtocolViolation(format!(
+                    "{} answered to the {} proposal",
+                    message, self.protocol
+                )));
+            }
+            session.publish(multistream_event(self.protocol, EventDirection::IN))?;
+            self.accepted = true;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+impl Libp2p {
+    /// Prepares the handshake with the node of the `/ip4/<ip>/tcp/<port>/p2p/<peer id>`
+    /// like multiaddr. The peer id is only checked when present. A new identity is used
+    /// for every handshake.
+    pub fn new(multiaddr: &str, identify: bool) -> Result<Libp2p, P2PError> {
+        let (node_addr, peer_id) = parse_multiaddr(multiaddr)?;
+        Ok(Libp2p {
+            node_addr,
+            peer_id,
+            identify,
+            identity: Identity::generate(),
+            transport: None,
+            plaintext: BytesMut::new(),
+            stage: Stage::Connect,
+        })
+    }
+
+    /// Overrides the configuration with the options of a single target, like
+    /// `identify=false`.
+    pub fn apply_options(
+        &mut self,
+        target: &str,
+        options: &[(String, String)],
+    ) -> Result<(), P2PError> {
+        for (key, val) in options {
+            let invalid_option = |reason: String| P2PError::InvalidTarget {
+                target: target.to_string(),
+                reason,
+            };
+            match key.as_str() {
+                "identify" => {
+                    self.identify = val
+                        .parse()
+                        .map_err(|_| invalid_option(format!("invalid identify {}", val)))?
+                }
+                _ => return Err(invalid_option(format!("unknown option {}", key))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts the Noise handshake once the security protocol was negotiated.
+    fn start_noise(&mut self, session: &mut Session) -> Result<(), P2PError> {
+        let builder = snow::Builder::new(NOISE_PARAMS.parse()?);
+        let static_key = builder.generate_keypair()?;
+        let mut handshake = builder
+            .local_private_key(&static_key.private)
+            .build_initiator()?;
+
+        let message = noise::write_handshake(&mut handshake, &[])?;
+        session.send(
+            Event::new("noise".to_string(), EventDirection::OUT),
+            message,
+        )?;
+        self.stage = Stage::Noise {
+            handshake: Box::new(handshake),
+            payload: self.identity.payload(&static_key.public),
+        };
+        Ok(())
+    }
+
+    /// Handles the Noise message of the peer, which carries its identity, answering
+    /// it with ours and proposing the muxer over the secured connection.
+    fn handle_noise(
+        &mut self,
+        mut handshake: HandshakeState,
+        payload: &[u8],
+        message: &[u8],
+        session: &mut Session,
+    ) -> Result<(), P2PError> {
+        let peer_payload = noise::read_handshake(&mut handshake, message)?;
+        let peer_static_key = handshake.get_remote_static().unwrap_or_default();
+        let peer_id = noise::verify_payload(&peer_payload, peer_static_key)?;
+
+        let mut event = Event::new("noise".to_string(), EventDirection::IN);
+        event.set_pair("peer-id".to_string(), peer_id.clone());
+        session.publish(event)?;
+        if let Some(expected) = &self.peer_id {
+            if *expected != peer_id {
+                return Err(P2PError::AuthenticationFailed(format!(
+                    "peer id {} does not match the expected {}",
+                    peer_id, expected
+                )));
+            }
+        }
+
+        let message = noise::write_handshake(&mut handshake, payload)?;
+        session.send(
+            Event::new("noise".to_string(), EventDirection::OUT),
+            message,
+        )?;
+
+        self.transport = Some(Box::new(handshake.into_transport_mode()?));
+        self.send_secured(
+            multistream_event(YAMUX, EventDirection::OUT),
+            &wire::multistream_proposal(YAMUX),
+            session,
+        )?;
+        self.stage = Stage::Muxer(Negotiation::new(YAMUX));
+        Ok(())
+    }
+
+    /// Opens the identify stream once the muxer was negotiated, or finishes otherwise.
+    fn start_identify(&mut self, session: &mut Session) -> Result<(), P2PError> {
+        if !self.identify {
+            self.stage = Stage::Complete;
+            return Ok(());
+        }
+        let frame = wire::yamux_frame(
+            YAMUX_DATA,
+            YAMUX_SYN,
+            IDENTIFY_STREAM_ID,
+            &wire::multistream_proposal(IDENTIFY),
+        );
+        self.send_secured(
+            multistream_event(IDENTIFY, EventDirection::OUT),
+            &frame,
+            session,
+        )?;
+        self.stage = Stage::Identify {
+            stream: BytesMut::new(),
+            negotiation: Negotiation::new(IDENTIFY),
+        };
+        Ok(())
+    }
+
+    fn send_secured(
+        &mut self,
+        event: Event,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), P2PError> {
+        let transport = self
+            .transport
+            .as_mut()
+            .ok_or_else(|| P2PError::internal("connection not secured yet", None))?;
+        session.send(event, noise::encrypt(transport, data)?)
+    }
+}
+
+impl Protocol for Libp2p {
+    fn default_port(&self) -> u16 {
+        4001
+    }
+
+    fn node_addr<'a>(&'a self, _target: &'a str) -> &'a str {
+        &self.node_addr
+    }
+
+    fn start(&mut self, session: &mut Session) -> Result<(), P2PError> {
+        let event = multistream_event(NOISE, EventDirection::OUT);
+        session.send(event, wire::multistream_proposal(NOISE))?;
+        self.stage = Stage::Security(Negotiation::new(NOISE));
+        Ok(())
+    }
+
+    fn on_data(&mut self, buffer: &mut BytesMut, session: &mut Session) -> Result<(), P2PError> {
+        loop {
+            if let Some(transport) = &mut self.transport {
+                noise::decrypt(transport, buffer, &mut self.plaintext)?;
+            }
+            match &mut self.stage {
+                Stage::Security(negotiation) => {
+                    if !negotiation.on_data(buffer, session)? {
+                        return Ok(());
+                    }
+                    self.start_noise(session)?;
+                }
+                Stage::Noise { .. } => {
+                    let message = match noise::read_frame(buffer) {
+                        Some(message) => message,
+                        None => return Ok(()),
+                    };
+                    if let Stage::Noise { handshake, payload } =
+                        std::mem::replace(&mut self.stage, Stage::Connect)
+                    {
+                        self.handle_noise(*handshake, &payload, &message, session)?;
+                    }
+                }
+                Stage::Muxer(negotiation) => {
+                    if !negotiation.on_data(&mut self.plaintext, session)? {
+                        return Ok(());
+                    }
+                    self.start_identify(session)?;
+                }
+                Stage::Identify {
+                    stream,
+                    negotiation,
+                } => {
+                    let (header, data) = match wire::read_yamux(&mut self.plaintext)? {
+                        Some(frame) => frame,
+                        None => return Ok(()),
+                    };
+                    if header.kind == YAMUX_GO_AWAY {
+                        return Err(P2PError::PeerClosed(Some(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            format!("yamux session terminated with code {}", header.length),
+                        ))));
+                    }
+                    // Other streams the peer may open, like its own identify, and the
+                    // control frames are not needed for the handshake.
+                    if header.stream_id != IDENTIFY_STREAM_ID {
+                        continue;
+                    }
+                    if header.flags & YAMUX_RST != 0 {
+                        return Err(P2PError::ProtocolViolation(
+                            "identify stream reset by the peer".to_string(),
+                        ));
+                    }
+                    stream.extend_from_slice(&data);
+                    // Once the protocol is accepted, what follows is the identify message.
+                    if !negotiation.on_data(stream, session)? {
+                        continue;
+                    }
+                    if let Some(message) = wire::read_prefixed(stream, MAX_IDENTIFY_LEN)? {
+                        session.publish(identify_event(&message)?)?;
+                        self.stage = Stage::Complete;
+                    }
+                }
+                Stage::Connect | Stage::Complete => return Ok(()),
+            }
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        matches!(self.stage, Stage::Complete)
+    }
+}
+
+fn multistream_event(protocol: &str, direction: EventDirection) -> Event {
+    let mut event = Event::new("multistream".to_string(), direction);
+    event.set_pair("proto".to_string(), protocol.to_string());
+    event
+}
+
+/// The event of the identify message, with the peer agent and supported protocols.
+fn identify_event(message: &[u8]) -> Result<Event, P2PError> {
+    let string = |value: &[u8]| String::from_utf8_lossy(value).into_owned();
+    let (mut protocol_version, mut agent_version, mut protocols) = (None, None, Vec::new());
+    for (field, value) in wire::fields(message)? {
+        match (field, value) {
+            (3, Value::Bytes(value)) => protocols.push(string(value)),
+            (5, Value::Bytes(value)) => protocol_version = Some(string(value)),
+            (6, Value::Bytes(value)) => agent_version = Some(string(value)),
+            _ => {}
+        }
+    }
+    let mut event = Event::new("identify".to_string(), EventDirection::IN);
+    if let Some(protocol_version) = protocol_version {
+        event.set_pair("protocol-version".to_string(), protocol_version);
+    }
+    if let Some(agent_version) = agent_version {
+        event.set_pair("agent-version".to_string(), agent_version);
+    }
+    event.set_pair("protocols".to_string(), protocols);
+    Ok(event)
+}
+
+/// Splits a `/ip4/<ip>/tcp/<port>/p2p/<peer id>` like multiaddr in the node address and
+/// the peer id, if any. Hosts can be also given with `/ip6` and `/dns`, `/dns4` or `/dns6`.
+fn parse_multiaddr(multiaddr: &str) -> Result<(String, Option<String>), P2PError> {
+    let invalid_multiaddr = |reason: String| P2PError::InvalidTarget {
+        target: multiaddr.to_string(),
+        reason,
+    };
+    let mut components = multiaddr
+        .strip_prefix('/')
+        .ok_or_else(|| invalid_multiaddr("not like /ip4/<ip>/tcp/<port>".to_string()))?
+        .split('/');
+
+    let (mut host, mut port, mut peer_id) = (None, None, None);
+    while let Some(protocol) = components.next() {
+        let value = components
+            .next()
+            .ok_or_else(|| invalid_multiaddr(format!("missing {} value", protocol)))?;
+        let invalid_value = || invalid_multiaddr(format!("invalid {} {}", protocol, value));
+        match protocol {
+            "ip4" if host.is_none() => {
+                value.parse::<Ipv4Addr>().map_err(|_| invalid_value())?;
+                host = Some(value.to_string());
+            }
+            "ip6" if host.is_none() => {
+                value.parse::<Ipv6Addr>().map_err(|_| invalid_value())?;
+                host = Some(format!("[{}]", value));
+            }
+            "dns" | "dns4" | "dns6" if host.is_none() => host = Some(value.to_string()),
+            "tcp" if host.is_some() && port.is_none() => {
+                port = Some(value.parse::<u16>().map_err(|_| invalid_value())?)
+            }
+            "p2p" | "ipfs" if peer_id.is_none() => {
+                bs58::decode(value)
+                    .into_vec()
+                    .map_err(|_| invalid_value())?;
+                peer_id = Some(value.to_string());
+            }
+            _ => {
+                return Err(invalid_multiaddr(format!(
+                    "unsupported {} component",
+                    protocol
+                )))
+            }
+        }
+    }
+    match (host, port) {
+        (Some(host), Some(port)) => Ok((format!("{}:{}", host, port), peer_id)),
+        _ => Err(invalid_multiaddr(
+            "only TCP multiaddrs are supported".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::p2p::protocol::{
+        run,
+        tests::{peer, TIMEOUTS},
+    };
+
+    /// Reads from the stream until the parser returns a message. Nothing is returned
+    /// if the connection is closed before.
+    async fn read<T>(
+        stream: &mut TcpStream,
+        buffer: &mut BytesMut,
+        mut parse: impl FnMut(&mut BytesMut) -> Option<T>,
+    ) -> Option<T> {
+        loop {
+            if let Some(message) = parse(buffer) {
+                return Some(message);
+            }
+            if stream.read_buf(buffer).await.ok()? == 0 {
+                return None;
+            }
+        }
+    }
+
+    /// Answers the connection upgrade of a single dialer with the provided identity,
+    /// sending the identify message if the stream is opened.
+    async fn respond(mut stream: TcpStream, identity: Identity, identify: Vec<u8>) -> Option<()> {
+        let mut buffer = BytesMut::new();
+        for expected in [MULTISTREAM, NOISE] {
+            let message = read(&mut stream, &mut buffer, |buffer| {
+                wire::read_multistream(buffer).unwrap()
+            })
+            .await?;
+            assert_eq!(expected, message);
+        }
+        stream
+            .write_all(&wire::multistream_proposal(NOISE))
+            .await
+            .ok()?;
+
+        let builder = snow::Builder::new(NOISE_PARAMS.parse().unwrap());
+        let static_key = builder.generate_keypair().unwrap();
+        let mut handshake = builder
+            .local_private_key(&static_key.private)
+            .build_responder()
+            .unwrap();
+        let message = read(&mut stream, &mut buffer, noise::read_frame).await?;
+        noise::read_handshake(&mut handshake, &message).unwrap();
+        let message =
+            noise::write_handshake(&mut handshake, &identity.payload(&static_key.public)).unwrap();
+        stream.write_all(&message).await.ok()?;
+        let message = read(&mut stream, &mut buffer, noise::read_frame).await?;
+        let payload = noise::read_handshake(&mut handshake, &message).ok()?;
+        noise::verify_payload(&payload, handshake.get_remote_static().unwrap()).unwrap();
+
+        let mut transport = handshake.into_transport_mode().unwrap();
+        let mut plaintext = BytesMut::new();
+        for expected in [MULTISTREAM, YAMUX] {
+            let message = read(&mut stream, &mut buffer, |buffer| {
+                noise::decrypt(&mut transport, buffer, &mut plaintext).unwrap();
+                wire::read_multistream(&mut plaintext).unwrap()
+            })
+            .await?;
+            assert_eq!(expected, message);
+        }
+        let answer = noise::encrypt(&mut transport, &wire::multistream_proposal(YAMUX)).unwrap();
+        stream.write_all(&answer).await.ok()?;
+
+        let (header, data) = read(&mut stream, &mut buffer, |buffer| {
+            noise::decrypt(&mut transport, buffer, &mut plaintext).unwrap();
+            wire::read_yamux(&mut plaintext).unwrap()
+        })
+        .await?;
+        assert_eq!(IDENTIFY_STREAM_ID, header.stream_id);
+        assert_eq!(wire::multistream_proposal(IDENTIFY), data);
+        // The peers usually open streams of their own, which must be ignored.
+        let mut answer = wire::yamux_frame(YAMUX_DATA, YAMUX_SYN, 2, b"ignored");
+        let mut identify_data = wire::multistream_proposal(IDENTIFY);
+        wire::put_varint(&mut identify_data, identify.len() as u64);
+        identify_data.extend(identify);
+        answer.extend(wire::yamux_frame(
+            YAMUX_DATA,
+            0,
+            IDENTIFY_STREAM_ID,
+            &identify_data,
+        ));
+        let answer = noise::encrypt(&mut transport, &answer).unwrap();
+        stream.write_all(&answer).await.ok()?;
+        // Wait for the dialer to close the connection.
+        stream.read_buf(&mut buffer).await.ok()?;
+        Some(())
+    }
+
+    /// Starts a node answering the connection upgrade, returning its multiaddr with the
+    /// provided peer id, or its own one if none is provided.
+    async fn responder(peer_id: Option<&str>, identify: Vec<u8>) -> String {
+        let identity = Identity::generate();
+        let peer_id = match peer_id {
+            Some(peer_id) => peer_id.to_string(),
+            None => noise::peer_id(&identity.public_key()),
+        };
+        let (addr, _) = peer(|stream| respond(stream, identity, identify)).await;
+        format!("/ip4/{}/tcp/{}/p2p/{}", addr.ip(), addr.port(), peer_id)
+    }
+
+    fn identify_message() -> Vec<u8> {
+        let mut message = Vec::new();
+        wire::put_field(&mut message, 5, b"ipfs/0.1.0");
+        wire::put_field(&mut message, 6, b"kubo/0.24.0/");
+        for protocol in [IDENTIFY, "/ipfs/ping/1.0.0", "/ipfs/kad/1.0.0"] {
+            wire::put_field(&mut message, 3, protocol.as_bytes());
+        }
+        message
+    }
+
+    fn messages(result: &crate::p2p::view::HandshakeResult) -> Vec<(String, String)> {
+        result
+            .event_chain()
+            .events()
+            .iter()
+            .filter(|ev| !matches!(ev.direction(), EventDirection::INTERNAL))
+            .map(|ev| (ev.name().to_string(), ev.direction().to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn libp2p_upgrades_the_connection_and_identifies_the_peer() {
+        let multiaddr = responder(None, identify_message()).await;
+
+        let libp2p = Libp2p::new(&multiaddr, true).unwrap();
+        let result = run(multiaddr.clone(), TIMEOUTS, libp2p).await;
+
+        assert!(result.event_chain().is_complete(), "{:?}", result.error());
+        let (out, r#in) = (
+            EventDirection::OUT.to_string(),
+            EventDirection::IN.to_string(),
+        );
+        let expected: Vec<(String, String)> = [
+            ("multistream", &out),
+            ("multistream", &r#in),
+            ("noise", &out),
+            ("noise", &r#in),
+            ("noise", &out),
+            ("multistream", &out),
+            ("multistream", &r#in),
+            ("multistream", &out),
+            ("multistream", &r#in),
+            ("identify", &r#in),
+        ]
+        .iter()
+        .map(|(name, direction)| (name.to_string(), direction.to_string()))
+        .collect();
+        assert_eq!(expected, messages(&result));
+
+        let events = result.event_chain().events();
+        let peer_id = multiaddr.rsplit('/').next().unwrap();
+        let peer_noise = events
+            .iter()
+            .find(|ev| ev.name() == "noise" && matches!(ev.direction(), EventDirection::IN))
+            .unwrap();
+        assert_eq!(
+            &[("peer-id".to_string(), peer_id.into())],
+            peer_noise.data_pairs()
+        );
+        assert_eq!(
+            &[
+                ("protocol-version".to_string(), "ipfs/0.1.0".into()),
+                ("agent-version".to_string(), "kubo/0.24.0/".into()),
+                (
+                    "protocols".to_string(),
+                    ["/ipfs/id/1.0.0", "/ipfs/ping/1.0.0", "/ipfs/kad/1.0.0"]
+                        .map(String::from)
+                        .to_vec()
+                        .into()
+                ),
+            ],
+            events
+                .iter()
+                .find(|ev| ev.name() == "identify")
+                .unwrap()
+                .data_pairs()
+        );
+    }
+
+    #[tokio::test]
+    async fn libp2p_can_skip_identify() {
+        let multiaddr = responder(None, identify_message()).await;
+
+        let libp2p = Libp2p::new(&multiaddr, false).unwrap();
+        let result = run(multiaddr, TIMEOUTS, libp2p).await;
+
+        assert!(result.event_chain().is_complete(), "{:?}", result.error());
+        let last = messages(&result).pop().unwrap();
+        assert_eq!(
+            ("multistream".to_string(), EventDirection::IN.to_string()),
+            last
+        );
+    }
+
+    #[tokio::test]
+    async fn libp2p_rejects_unexpected_peer_ids() {
+        let expected = "12D3KooWBtg3aaRMjxwedh83aGiUkwSxDwUZkzuJcfaqUmo7R3pq";
+        let multiaddr = responder(Some(expected), identify_message()).await;
+
+        let libp2p = Libp2p::new(&multiaddr, true).unwrap();
+        let result = run(multiaddr, TIMEOUTS, libp2p).await;
+
+        assert!(!result.event_chain().is_complete());
+        assert!(matches!(
+            result.error(),
+            Some(P2PError::AuthenticationFailed(reason)) if reason.ends_with(expected)
+        ));
+    }
+
+    #[test]
+    fn parse_multiaddr_extracts_address_and_peer_id() {
+        let cases = [
+            ("/ip4/127.0.0.1/tcp/4001", "127.0.0.1:4001", None),
+            (
+                "/ip6/::1/tcp/4001/p2p/12D3KooWBtg3aaRMjxwedh83aGiUkwSxDwUZkzuJcfaqUmo7R3pq",
+                "[::1]:4001",
+                Some("12D3KooWBtg3aaRMjxwedh83aGiUkwSxDwUZkzuJcfaqUmo7R3pq".to_string()),
+            ),
+            (
+                "/dns4/bootstrap.libp2p.io/tcp/443",
+                "bootstrap.libp2p.io:443",
+                None,
+            ),
+        ];
+        for (multiaddr, node_addr, peer_id) in cases {
+            assert_eq!(
+                (node_addr.to_string(), peer_id),
+                parse_multiaddr(multiaddr).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn parse_multiaddr_rejects_unsupported_multiaddrs() {
+        for multiaddr in [
+            "127.0.0.1:4001",
+            "/ip4/127.0.0.1",
+            "/ip4/127.0.0.1/udp/4001/quic-v1",
+            "/ip4/127.0.0.1/tcp/4001/ws",
+            "/ip4/localhost/tcp/4001",
+            "/ip4/127.0.0.1/tcp/4001/p2p/0OIl",
+        ] {
+            assert!(
+                matches!(
+                    parse_multiaddr(multiaddr),
+                    Err(P2PError::InvalidTarget { .. })
+                ),
+                "{}",
+                multiaddr
+            );
+        }
+    }
+}
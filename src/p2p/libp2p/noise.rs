@@ -0,0 +1,236 @@
+// The libp2p Noise security handshake: the Noise XX pattern over X25519, ChaChaPoly and
+// SHA256, in which each side proves its libp2p identity by signing its Noise static key
+// in the handshake payload. See <https://github.com/libp2p/specs/blob/master/noise/README.md>.
+
+use bytes::{Buf, BytesMut};
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use secp256k1::{ecdsa, Message, PublicKey, Secp256k1};
+use sha2::{Digest, Sha256};
+use snow::{HandshakeState, TransportState};
+
+use super::wire::{self, Value};
+use crate::p2p::P2PError;
+
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+
+/// What the identity keys sign, followed by the Noise static key.
+const SIGNATURE_PREFIX: &[u8] = b"noise-libp2p-static-key:";
+
+const MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+
+const ED25519_KEY: u64 = 1;
+const SECP256K1_KEY: u64 = 2;
+
+/// Identity keys up to this encoded length are inlined in the peer id instead of hashed.
+const MAX_INLINE_KEY_LEN: usize = 42;
+
+/// A libp2p identity backed by an Ed25519 key.
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Identity {
+        Identity {
+            key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// The protobuf encoded public key, as known by the peers.
+    pub fn public_key(&self) -> Vec<u8> {
+        let mut public_key = Vec::new();
+        wire::put_varint_field(&mut public_key, 1, ED25519_KEY);
+        wire::put_field(&mut public_key, 2, self.key.verifying_key().as_bytes());
+        public_key
+    }
+
+    /// The handshake payload proving that the identity owns the Noise static key.
+    pub fn payload(&self, static_key: &[u8]) -> Vec<u8> {
+        let signature = self.key.sign(&[SIGNATURE_PREFIX, static_key].concat());
+        let mut payload = Vec::new();
+        wire::put_field(&mut payload, 1, &self.public_key());
+        wire::put_field(&mut payload, 2, &signature.to_bytes());
+        payload
+    }
+}
+
+/// Validates that the handshake payload of the peer signs its Noise static key,
+/// returning the peer id of its identity.
+pub fn verify_payload(payload: &[u8], static_key: &[u8]) -> Result<String, P2PError> {
+    let (mut identity_key, mut signature) = (None, None);
+    for (field, value) in wire::fields(payload)? {
+        match (field, value) {
+            (1, Value::Bytes(value)) => identity_key = Some(value),
+            (2, Value::Bytes(value)) => signature = Some(value),
+            _ => {}
+        }
+    }
+    match (identity_key, signature) {
+        (Some(identity_key), Some(signature)) => {
+            verify(
+                identity_key,
+                signature,
+                &[SIGNATURE_PREFIX, static_key].concat(),
+            )?;
+            Ok(peer_id(identity_key))
+        }
+        _ => Err(P2PError::ProtocolViolation(
+            "noise handshake payload without identity".to_string(),
+        )),
+    }
+}
+
+/// Verifies the signature of the message with the protobuf encoded identity key.
+fn verify(identity_key: &[u8], signature: &[u8], message: &[u8]) -> Result<(), P2PError> {
+    let (mut key_type, mut key) = (None, None);
+    for (field, value) in wire::fields(identity_key)? {
+        match (field, value) {
+            (1, Value::Varint(value)) => key_type = Some(value),
+            (2, Value::Bytes(value)) => key = Some(value),
+            _ => {}
+        }
+    }
+    fn invalid_signature<E>(_: E) -> P2PError {
+        P2PError::ProtocolViolation("invalid identity signature".to_string())
+    }
+    match (key_type, key) {
+        (Some(ED25519_KEY), Some(key)) => {
+            let key = VerifyingKey::try_from(key).map_err(invalid_signature)?;
+            let signature =
+                ed25519_dalek::Signature::from_slice(signature).map_err(invalid_signature)?;
+            key.verify(message, &signature).map_err(invalid_signature)
+        }
+        (Some(SECP256K1_KEY), Some(key)) => {
+            let key = PublicKey::from_slice(key).map_err(invalid_signature)?;
+            let mut signature = ecdsa::Signature::from_der(signature).map_err(invalid_signature)?;
+            signature.normalize_s();
+            let message = Message::from_slice(&Sha256::digest(message)).expect("32 bytes message");
+            Secp256k1::verification_only()
+                .verify_ecdsa(&message, &signature, &key)
+                .map_err(invalid_signature)
+        }
+        (Some(key_type), Some(_)) => Err(P2PError::ProtocolViolation(format!(
+            "unsupported identity key type {}",
+            key_type
+        ))),
+        _ => Err(P2PError::Decode("malformed identity key".into())),
+    }
+}
+
+/// The base58 peer id of a protobuf encoded identity key. Short keys are inlined in an
+/// identity multihash, while longer ones are hashed with SHA256.
+pub fn peer_id(identity_key: &[u8]) -> String {
+    let mut multihash = Vec::new();
+    if identity_key.len() <= MAX_INLINE_KEY_LEN {
+        multihash.push(0x00);
+        wire::put_varint(&mut multihash, identity_key.len() as u64);
+        multihash.extend_from_slice(identity_key);
+    } else {
+        multihash.extend_from_slice(&[0x12, 0x20]);
+        multihash.extend_from_slice(&Sha256::digest(identity_key));
+    }
+    bs58::encode(multihash).into_string()
+}
+
+/// Frames a Noise message with its two bytes length prefix.
+pub fn frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = (message.len() as u16).to_be_bytes().to_vec();
+    frame.extend_from_slice(message);
+    frame
+}
+
+/// Takes the next Noise message from the buffer, without its length prefix.
+pub fn read_frame(buffer: &mut BytesMut) -> Option<BytesMut> {
+    if buffer.len() < 2 {
+        return None;
+    }
+    let len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+    if buffer.len() < 2 + len {
+        return None;
+    }
+    buffer.advance(2);
+    Some(buffer.split_to(len))
+}
+
+/// Writes the next handshake message with the provided payload, framed.
+pub fn write_handshake(
+    handshake: &mut HandshakeState,
+    payload: &[u8],
+) -> Result<Vec<u8>, P2PError> {
+    let mut message = vec![0; MAX_MESSAGE_LEN];
+    let len = handshake.write_message(payload, &mut message)?;
+    Ok(frame(&message[..len]))
+}
+
+/// Reads a handshake message of the peer, returning its payload.
+pub fn read_handshake(handshake: &mut HandshakeState, message: &[u8]) -> Result<Vec<u8>, P2PError> {
+    let mut payload = vec![0; message.len()];
+    let len = handshake.read_message(message, &mut payload)?;
+    payload.truncate(len);
+    Ok(payload)
+}
+
+/// Encrypts the data for the peer once secured, in as many frames as needed.
+pub fn encrypt(transport: &mut TransportState, data: &[u8]) -> Result<Vec<u8>, P2PError> {
+    let mut frames = Vec::new();
+    for chunk in data.chunks(MAX_MESSAGE_LEN - TAG_LEN) {
+        let mut message = vec![0; chunk.len() + TAG_LEN];
+        let len = transport.write_message(chunk, &mut message)?;
+        frames.extend(frame(&message[..len]));
+    }
+    Ok(frames)
+}
+
+/// Decrypts the complete frames of the buffer, appending their data to the plain text.
+pub fn decrypt(
+    transport: &mut TransportState,
+    buffer: &mut BytesMut,
+    plaintext: &mut BytesMut,
+) -> Result<(), P2PError> {
+    while let Some(message) = read_frame(buffer) {
+        let mut data = vec![0; message.len()];
+        let len = transport.read_message(&message, &mut data)?;
+        plaintext.extend_from_slice(&data[..len]);
+    }
+    Ok(())
+}
+
+impl From<snow::Error> for P2PError {
+    fn from(err: snow::Error) -> Self {
+        P2PError::Decode(Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_are_verified_against_the_static_key() {
+        let identity = Identity::generate();
+        let payload = identity.payload(&[1; 32]);
+
+        assert_eq!(
+            peer_id(&identity.public_key()),
+            verify_payload(&payload, &[1; 32]).unwrap()
+        );
+        assert!(matches!(
+            verify_payload(&payload, &[2; 32]),
+            Err(P2PError::ProtocolViolation(_))
+        ));
+    }
+
+    #[test]
+    fn peer_ids_inline_ed25519_keys() {
+        let identity_key =
+            hex::decode("080112201ed1e8fae2c4a144b8be8fd4b47bf3d3b34b871c3cacf6010f0e42d474fce27e")
+                .unwrap();
+
+        assert_eq!(
+            "12D3KooWBtg3aaRMjxwedh83aGiUkwSxDwUZkzuJcfaqUmo7R3pq",
+            peer_id(&identity_key)
+        );
+    }
+}
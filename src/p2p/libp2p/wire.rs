@@ -0,0 +1,275 @@
+// The wire formats of the libp2p connection upgrade: the unsigned varints, the protobuf
+// fields of the handshake messages, the multistream-select negotiation messages and the
+// yamux frames. See <https://github.com/libp2p/specs>.
+//
+// Only the subset needed by the dialer side of the handshake is implemented.
+
+use bytes::{Buf, BytesMut};
+
+use crate::p2p::P2PError;
+
+/// The header every multistream-select negotiation starts with.
+pub const MULTISTREAM: &str = "/multistream/1.0.0";
+
+/// The longest multistream-select message we accept, protocol ids are way shorter.
+const MAX_MULTISTREAM_LEN: usize = 1024;
+
+const YAMUX_VERSION: u8 = 0;
+const YAMUX_HEADER_LEN: usize = 12;
+
+pub const YAMUX_DATA: u8 = 0;
+pub const YAMUX_GO_AWAY: u8 = 3;
+
+pub const YAMUX_SYN: u16 = 0x1;
+pub const YAMUX_RST: u16 = 0x8;
+
+/// The value of a protobuf field. Fixed size fields are not used by the handshake
+/// messages, so they are skipped.
+pub enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// The header of a yamux frame.
+#[derive(Debug, PartialEq, Eq)]
+pub struct YamuxHeader {
+    pub kind: u8,
+    pub flags: u16,
+    pub stream_id: u32,
+    /// The length of the data frames, or the value of the other ones.
+    pub length: u32,
+}
+
+pub fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Reads the varint at the start of the buffer, returning it along its length, or
+/// nothing if more bytes are needed.
+pub fn read_varint(buffer: &[u8]) -> Result<Option<(u64, usize)>, P2PError> {
+    let mut value = 0;
+    for (i, byte) in buffer.iter().enumerate() {
+        // Unsigned varints are limited to 9 bytes.
+        if i == 9 {
+            return Err(P2PError::Decode("varint too long".into()));
+        }
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+    }
+    Ok(None)
+}
+
+/// Takes the next length prefixed message from the buffer, without its prefix.
+pub fn read_prefixed(buffer: &mut BytesMut, max_len: usize) -> Result<Option<BytesMut>, P2PError> {
+    let (len, prefix_len) = match read_varint(buffer)? {
+        Some(varint) => varint,
+        None => return Ok(None),
+    };
+    let len = usize::try_from(len).unwrap_or(usize::MAX);
+    if len > max_len {
+        return Err(P2PError::Decode(
+            format!("message of {} bytes exceeds the limit of {}", len, max_len).into(),
+        ));
+    }
+    if buffer.len() < prefix_len + len {
+        return Ok(None);
+    }
+    buffer.advance(prefix_len);
+    Ok(Some(buffer.split_to(len)))
+}
+
+/// Appends a length delimited protobuf field.
+pub fn put_field(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    put_varint(out, field << 3 | 2);
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+/// Appends a varint protobuf field.
+pub fn put_varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(out, field << 3);
+    put_varint(out, value);
+}
+
+/// Decodes the fields of a protobuf message, in the order they were encoded.
+pub fn fields(mut message: &[u8]) -> Result<Vec<(u64, Value<'_>)>, P2PError> {
+    let truncated = || P2PError::Decode("truncated protobuf message".into());
+    let varint = |message: &mut &[u8]| {
+        let (value, len) = read_varint(message)?.ok_or_else(truncated)?;
+        *message = &message[len..];
+        Ok::<u64, P2PError>(value)
+    };
+
+    let mut fields = Vec::new();
+    while !message.is_empty() {
+        let key = varint(&mut message)?;
+        let value = match key & 0x07 {
+            0 => Value::Varint(varint(&mut message)?),
+            2 => {
+                let len = usize::try_from(varint(&mut message)?).unwrap_or(usize::MAX);
+                let value = message.get(..len).ok_or_else(truncated)?;
+                message = &message[len..];
+                Value::Bytes(value)
+            }
+            wire_type @ (1 | 5) => {
+                let len = if wire_type == 1 { 8 } else { 4 };
+                message = message.get(len..).ok_or_else(truncated)?;
+                continue;
+            }
+            wire_type => {
+                return Err(P2PError::Decode(
+                    format!("unsupported protobuf wire type {}", wire_type).into(),
+                ))
+            }
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+/// The multistream-select messages proposing a protocol, preceded by the header.
+/// Sending both at once saves a round trip when the peer supports the protocol.
+pub fn multistream_proposal(protocol: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    for message in [MULTISTREAM, protocol] {
+        put_varint(&mut out, message.len() as u64 + 1);
+        out.extend_from_slice(message.as_bytes());
+        out.push(b'\n');
+    }
+    out
+}
+
+/// Takes the next multistream-select message from the buffer, without its trailing newline.
+pub fn read_multistream(buffer: &mut BytesMut) -> Result<Option<String>, P2PError> {
+    let message = match read_prefixed(buffer, MAX_MULTISTREAM_LEN)? {
+        Some(message) => message,
+        None => return Ok(None),
+    };
+    match message.strip_suffix(b"\n") {
+        Some(message) => String::from_utf8(message.to_vec())
+            .map(Some)
+            .map_err(|err| P2PError::Decode(Box::new(err))),
+        None => Err(P2PError::Decode(
+            "multistream-select message without trailing newline".into(),
+        )),
+    }
+}
+
+/// A yamux frame with the provided header fields. The length is the one of the data.
+pub fn yamux_frame(kind: u8, flags: u16, stream_id: u32, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(YAMUX_HEADER_LEN + data.len());
+    frame.push(YAMUX_VERSION);
+    frame.push(kind);
+    frame.extend_from_slice(&flags.to_be_bytes());
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Takes the next yamux frame from the buffer, along its data for the data frames.
+pub fn read_yamux(buffer: &mut BytesMut) -> Result<Option<(YamuxHeader, BytesMut)>, P2PError> {
+    if buffer.len() < YAMUX_HEADER_LEN {
+        return Ok(None);
+    }
+    if buffer[0] != YAMUX_VERSION {
+        return Err(P2PError::Decode(
+            format!("unsupported yamux version {}", buffer[0]).into(),
+        ));
+    }
+    let header = YamuxHeader {
+        kind: buffer[1],
+        flags: u16::from_be_bytes([buffer[2], buffer[3]]),
+        stream_id: u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]),
+        length: u32::from_be_bytes([buffer[8], buffer[9], buffer[10], buffer[11]]),
+    };
+    let data_len = match header.kind {
+        YAMUX_DATA => header.length as usize,
+        _ => 0,
+    };
+    if buffer.len() < YAMUX_HEADER_LEN + data_len {
+        return Ok(None);
+    }
+    buffer.advance(YAMUX_HEADER_LEN);
+    let data = buffer.split_to(data_len);
+    Ok(Some((header, data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints_roundtrip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX >> 1] {
+            let mut out = Vec::new();
+            put_varint(&mut out, value);
+
+            assert_eq!(Some((value, out.len())), read_varint(&out).unwrap());
+            assert_eq!(None, read_varint(&out[..out.len() - 1]).unwrap());
+        }
+    }
+
+    #[test]
+    fn protobuf_fields_roundtrip() {
+        let mut message = Vec::new();
+        put_varint_field(&mut message, 1, 300);
+        // A fixed64 field, which is skipped.
+        message.extend_from_slice(&[0x11, 1, 2, 3, 4, 5, 6, 7, 8]);
+        put_field(&mut message, 3, b"/ipfs/id/1.0.0");
+
+        let fields = fields(&message).unwrap();
+
+        assert_eq!(2, fields.len());
+        assert!(matches!(fields[0], (1, Value::Varint(300))));
+        assert!(matches!(fields[1], (3, Value::Bytes(b"/ipfs/id/1.0.0"))));
+        assert!(matches!(
+            super::fields(&message[..message.len() - 1]),
+            Err(P2PError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn multistream_messages_are_read_once_complete() {
+        let proposal = multistream_proposal("/noise");
+        let mut buffer = BytesMut::from(&proposal[..proposal.len() - 1]);
+
+        assert_eq!(
+            Some(MULTISTREAM.to_string()),
+            read_multistream(&mut buffer).unwrap()
+        );
+        assert_eq!(None, read_multistream(&mut buffer).unwrap());
+        buffer.extend_from_slice(b"\n");
+        assert_eq!(
+            Some("/noise".to_string()),
+            read_multistream(&mut buffer).unwrap()
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn yamux_frames_roundtrip() {
+        let mut buffer = BytesMut::from(&yamux_frame(YAMUX_DATA, YAMUX_SYN, 1, b"data")[..]);
+        buffer.extend_from_slice(&yamux_frame(YAMUX_GO_AWAY, 0, 0, &[])[..11]);
+
+        let (header, data) = read_yamux(&mut buffer).unwrap().unwrap();
+
+        assert_eq!(
+            YamuxHeader {
+                kind: YAMUX_DATA,
+                flags: YAMUX_SYN,
+                stream_id: 1,
+                length: 4
+            },
+            header
+        );
+        assert_eq!(&b"data"[..], &data[..]);
+        assert_eq!(None, read_yamux(&mut buffer).unwrap());
+    }
+}
@@ -0,0 +1,497 @@
+use std::io;
+
+use bitcoin::{blockdata::constants::genesis_block, hashes::Hash, network::constants::Network};
+use bytes::BytesMut;
+use secp256k1::{All, PublicKey, Secp256k1, SecretKey};
+
+use crate::p2p::{
+    protocol::{Protocol, Session},
+    view::{Event, EventDirection},
+    P2PError,
+};
+
+use self::noise::{Handshake, Transport, ACT_TWO_LEN};
+
+mod noise;
+
+const INIT_MESSAGE_TYPE: u16 = 16;
+const ERROR_MESSAGE_TYPE: u16 = 17;
+
+/// The init TLV record with the chains the node is interested in.
+const NETWORKS_TLV_TYPE: u64 = 1;
+
+/// The features announced in our init message, all of them as optional: data loss
+/// protection, variable size onions, static remote keys, payment secrets and basic
+/// multi-part payments. So peers requiring any of them do not consider us useless.
+const FEATURE_BITS: [usize; 5] = [1, 9, 13, 15, 17];
+
+/// The Lightning Network handshake: the BOLT 8 Noise_XK acts followed by the
+/// exchange of the init messages.
+pub struct Ln {
+    node_addr: String,
+    remote_key: PublicKey,
+    network: Network,
+    secp: Secp256k1<All>,
+    key: SecretKey,
+    stage: Stage,
+}
+
+enum Stage {
+    /// Not connected yet.
+    Connect,
+    /// Waiting for the act two, once the act one was sent.
+    ActTwo(Box<Handshake>),
+    /// Waiting for the peer init, once the handshake finished and ours was sent.
+    Init(Box<Transport>),
+    Complete,
+}
+
+impl Ln {
+    /// Prepares the handshake with the node of the `<node public key>@<host>:<port>`
+    /// target. A new node key is used for every handshake.
+    pub fn new(target: &str, network: Network) -> Result<Ln, P2PError> {
+        let (remote_key, node_addr) = parse_target(target)?;
+        Ok(Ln {
+            node_addr,
+            remote_key,
+            network,
+            secp: Secp256k1::new(),
+            key: SecretKey::new(&mut rand::thread_rng()),
+            stage: Stage::Connect,
+        })
+    }
+
+    /// Overrides the configuration with the options of a single target, like
+    /// `network=testnet`.
+    pub fn apply_options(
+        &mut self,
+        target: &str,
+        options: &[(String, String)],
+    ) -> Result<(), P2PError> {
+        for (key, val) in options {
+            let invalid_option = |reason: String| P2PError::InvalidTarget {
+                target: target.to_string(),
+                reason,
+            };
+            match key.as_str() {
+                "network" => {
+                    self.network = val
+                        .parse()
+                        .map_err(|_| invalid_option(format!("unknown network {}", val)))?
+                }
+                _ => return Err(invalid_option(format!("unknown option {}", key))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the act two, answering it with the act three and our init message.
+    fn handle_act_two(
+        &mut self,
+        handshake: Handshake,
+        act_two: &[u8],
+        session: &mut Session,
+    ) -> Result<Transport, P2PError> {
+        session.publish(Event::new("act-two".to_string(), EventDirection::IN))?;
+        let (act_three, mut transport) =
+            noise::act_three(handshake, &self.secp, &self.key, act_two)?;
+        let event = Event::new("act-three".to_string(), EventDirection::OUT);
+        session.send(event, act_three)?;
+
+        let init = transport.encrypt(&init_message(self.network));
+        session.send(Event::new("init".to_string(), EventDirection::OUT), init)?;
+        Ok(transport)
+    }
+
+    /// Handles a message received before the peer init, which can only be the init
+    /// itself or an error.
+    fn handle_message(&mut self, message: &[u8], session: &mut Session) -> Result<(), P2PError> {
+        let mut payload = message;
+        let message_type = u16::from_be_bytes(take(&mut payload, 2)?.try_into().expect("2 bytes"));
+        match message_type {
+            INIT_MESSAGE_TYPE => {
+                let (features, chains) = read_init(payload)?;
+                let chains: Vec<String> = chains.iter().map(chain_name).collect();
+                let mut event = Event::new("init".to_string(), EventDirection::IN);
+                let features: Vec<String> = features.iter().map(usize::to_string).collect();
+                event.set_pair("features".to_string(), features);
+                if !chains.is_empty() {
+                    event.set_pair("chains".to_string(), chains.clone());
+                }
+                session.publish(event)?;
+                // Peers not announcing their chains are interested in all of them.
+                let network = self.network.to_string();
+                if !chains.is_empty() && !chains.contains(&network) {
+                    return Err(P2PError::WrongNetwork {
+                        expected: network,
+                        received: chains.join(", "),
+                    });
+                }
+                self.stage = Stage::Complete;
+                Ok(())
+            }
+            ERROR_MESSAGE_TYPE => {
+                take(&mut payload, 32)?;
+                let len = u16::from_be_bytes(take(&mut payload, 2)?.try_into().expect("2 bytes"));
+                let data = String::from_utf8_lossy(take(&mut payload, len as usize)?).into_owned();
+                let mut event = Event::new("error".to_string(), EventDirection::IN);
+                event.set_pair("data".to_string(), data.clone());
+                session.publish(event)?;
+                Err(P2PError::PeerClosed(Some(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    format!("error: {}", data),
+                ))))
+            }
+            _ => Err(P2PError::ProtocolViolation(format!(
+                "message {} received before init",
+                message_type
+            ))),
+        }
+    }
+}
+
+impl Protocol for Ln {
+    fn default_port(&self) -> u16 {
+        9735
+    }
+
+    fn node_addr<'a>(&'a self, _target: &'a str) -> &'a str {
+        &self.node_addr
+    }
+
+    fn start(&mut self, session: &mut Session) -> Result<(), P2PError> {
+        let ephemeral_key = SecretKey::new(&mut rand::thread_rng());
+        let (handshake, act_one) = noise::act_one(&self.secp, &self.remote_key, ephemeral_key);
+        let event = Event::new("act-one".to_string(), EventDirection::OUT);
+        session.send(event, act_one)?;
+        self.stage = Stage::ActTwo(Box::new(handshake));
+        Ok(())
+    }
+
+    fn on_data(&mut self, buffer: &mut BytesMut, session: &mut Session) -> Result<(), P2PError> {
+        loop {
+            match &mut self.stage {
+                Stage::ActTwo(_) => {
+                    if buffer.len() < ACT_TWO_LEN {
+                        return Ok(());
+                    }
+                    let act_two = buffer.split_to(ACT_TWO_LEN);
+                    if let Stage::ActTwo(handshake) =
+                        std::mem::replace(&mut self.stage, Stage::Connect)
+                    {
+                        let transport = self.handle_act_two(*handshake, &act_two, session)?;
+                        self.stage = Stage::Init(Box::new(transport));
+                    }
+                }
+                Stage::Init(transport) => match transport.decrypt(buffer)? {
+                    Some(message) => self.handle_message(&message, session)?,
+                    None => return Ok(()),
+                },
+                Stage::Connect | Stage::Complete => return Ok(()),
+            }
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        matches!(self.stage, Stage::Complete)
+    }
+
+    fn on_closed(&mut self, source: Option<io::Error>) -> P2PError {
+        // Nodes close the connection when they cannot decrypt the act one, which
+        // only happens when it was encrypted for another node key.
+        match self.stage {
+            Stage::ActTwo(_) => P2PError::AuthenticationFailed(format!(
+                "the peer closed the connection after the act one, {} is probably not its node key",
+                hex::encode(self.remote_key.serialize())
+            )),
+            _ => P2PError::PeerClosed(source),
+        }
+    }
+}
+
+/// Splits a `<node public key>@<host>:<port>` target in the node key and its address.
+fn parse_target(target: &str) -> Result<(PublicKey, String), P2PError> {
+    let invalid_target = |reason: &str| P2PError::InvalidTarget {
+        target: target.to_string(),
+        reason: reason.to_string(),
+    };
+    let (node_key, node_addr) = target
+        .split_once('@')
+        .ok_or_else(|| invalid_target("not like <node public key>@<host>:<port>"))?;
+    let remote_key = hex::decode(node_key)
+        .ok()
+        .and_then(|node_key| PublicKey::from_slice(&node_key).ok())
+        .ok_or_else(|| invalid_target("the node public key is not valid"))?;
+    Ok((remote_key, node_addr.to_string()))
+}
+
+/// The init message, announcing our features and the chain of the network.
+fn init_message(network: Network) -> Vec<u8> {
+    let max_bit = FEATURE_BITS.iter().max().copied().unwrap_or_default();
+    let mut features = vec![0u8; max_bit / 8 + 1];
+    let features_len = features.len();
+    for bit in FEATURE_BITS {
+        features[features_len - 1 - bit / 8] |= 1 << (bit % 8);
+    }
+
+    let mut init = INIT_MESSAGE_TYPE.to_be_bytes().to_vec();
+    // The global features are deprecated in favour of the features.
+    init.extend_from_slice(&0u16.to_be_bytes());
+    init.extend_from_slice(&(features.len() as u16).to_be_bytes());
+    init.extend(features);
+    init.push(NETWORKS_TLV_TYPE as u8);
+    init.push(32);
+    init.extend_from_slice(&chain_hash(network));
+    init
+}
+
+/// Reads the payload of an init message, returning the set feature bits and the
+/// announced chains. Both the global and the regular features are considered.
+fn read_init(mut payload: &[u8]) -> Result<(Vec<usize>, Vec<[u8; 32]>), P2PError> {
+    let mut features = Vec::new();
+    for _ in 0..2 {
+        let len = u16::from_be_bytes(take(&mut payload, 2)?.try_into().expect("2 bytes"));
+        let field = take(&mut payload, len as usize)?;
+        for (i, byte) in field.iter().rev().enumerate() {
+            features.extend(
+                (0..8)
+                    .filter(|bit| byte >> bit & 1 == 1)
+                    .map(|bit| i * 8 + bit),
+            );
+        }
+    }
+    features.sort_unstable();
+    features.dedup();
+
+    let mut chains = Vec::new();
+    while !payload.is_empty() {
+        let tlv_type = big_size(&mut payload)?;
+        let len = big_size(&mut payload)?;
+        let value = take(&mut payload, usize::try_from(len).unwrap_or(usize::MAX))?;
+        if tlv_type == NETWORKS_TLV_TYPE {
+            chains.extend(value.chunks_exact(32).map(|chain| {
+                let mut chain_hash = [0; 32];
+                chain_hash.copy_from_slice(chain);
+                chain_hash
+            }));
+        }
+    }
+    Ok((features, chains))
+}
+
+/// Reads a BigSize integer, the variable length encoding of the TLV records.
+fn big_size(payload: &mut &[u8]) -> Result<u64, P2PError> {
+    let len = match take(payload, 1)?[0] {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        value => return Ok(value as u64),
+    };
+    Ok(take(payload, len)?
+        .iter()
+        .fold(0, |value, byte| value << 8 | *byte as u64))
+}
+
+fn take<'a>(payload: &mut &'a [u8], len: usize) -> Result<&'a [u8], P2PError> {
+    if payload.len() < len {
+        return Err(P2PError::Decode("truncated message".into()));
+    }
+    let (taken, rest) = payload.split_at(len);
+    *payload = rest;
+    Ok(taken)
+}
+
+/// The chain hash of the network, which is the hash of its genesis block.
+fn chain_hash(network: Network) -> [u8; 32] {
+    genesis_block(network).block_hash().into_inner()
+}
+
+/// The name of the network of a chain hash, or its hex representation if unknown.
+fn chain_name(chain: &[u8; 32]) -> String {
+    [
+        Network::Bitcoin,
+        Network::Testnet,
+        Network::Signet,
+        Network::Regtest,
+    ]
+    .into_iter()
+    .find(|network| chain_hash(*network) == *chain)
+    .map(|network| network.to_string())
+    .unwrap_or_else(|| hex::encode(chain))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::p2p::protocol::{
+        run,
+        tests::{peer, TIMEOUTS},
+    };
+
+    /// Starts a node that answers the handshake with the provided key, sending the
+    /// provided message as its init. It returns the address of the node and the
+    /// init message it received, if the handshake finished.
+    async fn responder(
+        key: SecretKey,
+        answer: Vec<u8>,
+    ) -> (String, tokio::task::JoinHandle<Option<Vec<u8>>>) {
+        let (addr, handle) = peer(move |mut stream| async move {
+            let secp = Secp256k1::new();
+            let mut act_one = [0; noise::ACT_ONE_LEN];
+            stream.read_exact(&mut act_one).await.unwrap();
+            // Like real nodes, the connection is closed when the act one cannot be decrypted.
+            let ephemeral_key = SecretKey::new(&mut rand::thread_rng());
+            let (handshake, act_two) = noise::act_two(&secp, &key, ephemeral_key, &act_one).ok()?;
+            stream.write_all(&act_two).await.unwrap();
+
+            let mut act_three = [0; noise::ACT_THREE_LEN];
+            stream.read_exact(&mut act_three).await.unwrap();
+            let (_, mut transport) = noise::read_act_three(handshake, &act_three).unwrap();
+            stream.write_all(&transport.encrypt(&answer)).await.unwrap();
+
+            let mut buffer = BytesMut::new();
+            loop {
+                if let Some(message) = transport.decrypt(&mut buffer).unwrap() {
+                    return Some(message);
+                }
+                if stream.read_buf(&mut buffer).await.ok()? == 0 {
+                    return None;
+                }
+            }
+        })
+        .await;
+        (addr.to_string(), handle)
+    }
+
+    fn target(key: &SecretKey, addr: &str) -> String {
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), key);
+        format!("{}@{}", hex::encode(public_key.serialize()), addr)
+    }
+
+    #[tokio::test]
+    async fn ln_handshakes_with_lightning_node() {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (addr, responder) = responder(key, init_message(Network::Testnet)).await;
+        let target = target(&key, &addr);
+
+        let ln = Ln::new(&target, Network::Testnet).unwrap();
+        let result = run(target, TIMEOUTS, ln).await;
+
+        let event_chain = result.result().unwrap();
+        assert!(event_chain.is_complete());
+        let events: Vec<(&str, String)> = event_chain
+            .events()
+            .iter()
+            .filter(|ev| !matches!(ev.direction(), EventDirection::INTERNAL))
+            .map(|ev| (ev.name(), ev.direction().to_string()))
+            .collect();
+        let (out, r#in) = (
+            EventDirection::OUT.to_string(),
+            EventDirection::IN.to_string(),
+        );
+        assert_eq!(
+            vec![
+                ("act-one", out.clone()),
+                ("act-two", r#in.clone()),
+                ("act-three", out.clone())
+            ],
+            events[..3]
+        );
+        // Both init messages are sent at the same time, so they can be recorded in any order.
+        assert_eq!(2, events[3..].len());
+        assert!(events[3..].contains(&("init", out)));
+        assert!(events[3..].contains(&("init", r#in)));
+
+        let peer_init = event_chain
+            .events()
+            .iter()
+            .find(|ev| ev.name() == "init" && matches!(ev.direction(), EventDirection::IN))
+            .unwrap();
+        assert_eq!(
+            &[
+                (
+                    "features".to_string(),
+                    ["1", "9", "13", "15", "17"]
+                        .map(String::from)
+                        .to_vec()
+                        .into()
+                ),
+                ("chains".to_string(), vec!["testnet".to_string()].into()),
+            ],
+            peer_init.data_pairs()
+        );
+
+        let our_init = responder.await.unwrap().unwrap();
+        assert_eq!(init_message(Network::Testnet), our_init);
+    }
+
+    #[tokio::test]
+    async fn ln_reports_wrong_node_keys() {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (addr, responder) = responder(key, init_message(Network::Bitcoin)).await;
+        let other_key = SecretKey::new(&mut rand::thread_rng());
+        let target = target(&other_key, &addr);
+
+        let ln = Ln::new(&target, Network::Bitcoin).unwrap();
+        let result = run(target, TIMEOUTS, ln).await;
+
+        assert!(!result.event_chain().is_complete());
+        assert!(matches!(
+            result.error(),
+            Some(P2PError::AuthenticationFailed(_))
+        ));
+        assert_eq!(None, responder.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn ln_reports_peers_of_other_chains() {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (addr, _) = responder(key, init_message(Network::Signet)).await;
+        let target = target(&key, &addr);
+
+        let ln = Ln::new(&target, Network::Bitcoin).unwrap();
+        let result = run(target, TIMEOUTS, ln).await;
+
+        assert_eq!(
+            "P2P error: wrong network: expected bitcoin, peer answered with signet",
+            result.error().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn read_init_merges_features_and_reads_chains() {
+        let mut init = vec![0, 1, 0b0000_0010, 0, 2, 0b0000_0010, 0b1000_0001];
+        // An unknown TLV record, followed by the networks one.
+        init.extend_from_slice(&[3, 1, 0xff, 1, 64]);
+        init.extend_from_slice(&chain_hash(Network::Bitcoin));
+        init.extend_from_slice(&[7; 32]);
+
+        let (features, chains) = read_init(&init).unwrap();
+
+        assert_eq!(vec![0, 1, 7, 9], features);
+        assert_eq!("bitcoin", chain_name(&chains[0]));
+        assert_eq!(hex::encode([7; 32]), chain_name(&chains[1]));
+        assert!(matches!(
+            read_init(&init[..init.len() - 1]),
+            Err(P2PError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn parse_target_splits_node_key_and_address() {
+        let node_key = "03864ef025fde8fb587d989186ce6a4a186895ee44a926bfc370e2c366597a3f8f";
+        let (remote_key, node_addr) =
+            parse_target(&format!("{}@3.33.236.230:9735", node_key)).unwrap();
+
+        assert_eq!(node_key, hex::encode(remote_key.serialize()));
+        assert_eq!("3.33.236.230:9735", node_addr);
+        for target in ["3.33.236.230:9735", "abcd@3.33.236.230:9735"] {
+            assert!(matches!(
+                parse_target(target),
+                Err(P2PError::InvalidTarget { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,413 @@
+// The BOLT 8 encrypted and authenticated transport: the Noise_XK handshake over secp256k1,
+// ChaChaPoly and SHA256 in three acts, and the encryption of the messages sent after it.
+// See <https://github.com/lightning/bolts/blob/master/08-transport.md>.
+//
+// Both sides of the handshake are implemented, although the responder one is only used by tests.
+
+use bytes::BytesMut;
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    ChaCha20Poly1305, KeyInit,
+};
+use hkdf::Hkdf;
+use secp256k1::{ecdh::SharedSecret, All, PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+
+use crate::p2p::P2PError;
+
+const PROTOCOL_NAME: &[u8] = b"Noise_XK_secp256k1_ChaChaPoly_SHA256";
+const PROLOGUE: &[u8] = b"lightning";
+
+/// The only handshake version defined so far.
+const HANDSHAKE_VERSION: u8 = 0;
+
+pub const ACT_TWO_LEN: usize = 50;
+#[cfg(test)]
+pub const ACT_ONE_LEN: usize = 50;
+#[cfg(test)]
+pub const ACT_THREE_LEN: usize = 66;
+
+const PUBLIC_KEY_LEN: usize = 33;
+const TAG_LEN: usize = 16;
+
+/// The keys are rotated once they were used for this number of messages.
+const KEY_ROTATION: u64 = 1000;
+
+/// The state carried between the acts of the handshake.
+pub struct Handshake {
+    ck: [u8; 32],
+    h: [u8; 32],
+    temp_k: [u8; 32],
+    ephemeral_key: SecretKey,
+}
+
+impl Handshake {
+    fn new(responder_key: &PublicKey, ephemeral_key: SecretKey) -> Handshake {
+        let ck = sha256(&[PROTOCOL_NAME]);
+        let mut handshake = Handshake {
+            ck,
+            h: ck,
+            temp_k: [0; 32],
+            ephemeral_key,
+        };
+        handshake.mix_hash(PROLOGUE);
+        handshake.mix_hash(&responder_key.serialize());
+        handshake
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = sha256(&[&self.h, data]);
+    }
+
+    fn mix_key(&mut self, shared_secret: &[u8]) {
+        (self.ck, self.temp_k) = hkdf(&self.ck, shared_secret);
+    }
+
+    fn encrypt_and_hash(&mut self, nonce: u64, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = encrypt(&self.temp_k, nonce, &self.h, plaintext);
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, nonce: u64, ciphertext: &[u8]) -> Result<Vec<u8>, P2PError> {
+        let plaintext = decrypt(&self.temp_k, nonce, &self.h, ciphertext)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// The transport once the handshake is finished.
+    fn split(&self, initiator: bool) -> Transport {
+        let (initiator_key, responder_key) = hkdf(&self.ck, &[]);
+        let (sending_key, receiving_key) = match initiator {
+            true => (initiator_key, responder_key),
+            false => (responder_key, initiator_key),
+        };
+        Transport {
+            sending: CipherState::new(sending_key, self.ck),
+            receiving: CipherState::new(receiving_key, self.ck),
+            body_len: None,
+        }
+    }
+}
+
+/// Builds the act one the initiator starts the handshake with.
+pub fn act_one(
+    secp: &Secp256k1<All>,
+    remote_key: &PublicKey,
+    ephemeral_key: SecretKey,
+) -> (Handshake, Vec<u8>) {
+    let mut handshake = Handshake::new(remote_key, ephemeral_key);
+    let ephemeral = PublicKey::from_secret_key(secp, &handshake.ephemeral_key).serialize();
+    handshake.mix_hash(&ephemeral);
+    handshake.mix_key(&ecdh(remote_key, &handshake.ephemeral_key));
+    let tag = handshake.encrypt_and_hash(0, &[]);
+    (handshake, act(&[&ephemeral, &tag]))
+}
+
+/// Reads the act two of the responder, answering it with the act three, which
+/// authenticates the initiator. The transport is ready once it is sent.
+pub fn act_three(
+    mut handshake: Handshake,
+    secp: &Secp256k1<All>,
+    key: &SecretKey,
+    act_two: &[u8],
+) -> Result<(Vec<u8>, Transport), P2PError> {
+    let (remote_ephemeral, tag) = read_act(act_two, PUBLIC_KEY_LEN)?;
+    handshake.mix_hash(remote_ephemeral);
+    let remote_ephemeral =
+        PublicKey::from_slice(remote_ephemeral).map_err(|err| P2PError::Decode(Box::new(err)))?;
+    handshake.mix_key(&ecdh(&remote_ephemeral, &handshake.ephemeral_key));
+    handshake.decrypt_and_hash(0, tag).map_err(|_| {
+        P2PError::AuthenticationFailed("the act two of the peer could not be verified".to_string())
+    })?;
+
+    let public_key = PublicKey::from_secret_key(secp, key).serialize();
+    let encrypted_key = handshake.encrypt_and_hash(1, &public_key);
+    handshake.mix_key(&ecdh(&remote_ephemeral, key));
+    let tag = handshake.encrypt_and_hash(0, &[]);
+    Ok((act(&[&encrypted_key, &tag]), handshake.split(true)))
+}
+
+/// Reads the act one of the initiator, answering it with the act two.
+#[cfg(test)]
+pub fn act_two(
+    secp: &Secp256k1<All>,
+    key: &SecretKey,
+    ephemeral_key: SecretKey,
+    act_one: &[u8],
+) -> Result<(Handshake, Vec<u8>), P2PError> {
+    let mut handshake = Handshake::new(&PublicKey::from_secret_key(secp, key), ephemeral_key);
+    let (remote_ephemeral, tag) = read_act(act_one, PUBLIC_KEY_LEN)?;
+    handshake.mix_hash(remote_ephemeral);
+    let remote_ephemeral =
+        PublicKey::from_slice(remote_ephemeral).map_err(|err| P2PError::Decode(Box::new(err)))?;
+    handshake.mix_key(&ecdh(&remote_ephemeral, key));
+    handshake.decrypt_and_hash(0, tag)?;
+
+    let ephemeral = PublicKey::from_secret_key(secp, &handshake.ephemeral_key).serialize();
+    handshake.mix_hash(&ephemeral);
+    handshake.mix_key(&ecdh(&remote_ephemeral, &handshake.ephemeral_key));
+    let tag = handshake.encrypt_and_hash(0, &[]);
+    Ok((handshake, act(&[&ephemeral, &tag])))
+}
+
+/// Reads the act three of the initiator, returning its node key and the transport.
+#[cfg(test)]
+pub fn read_act_three(
+    mut handshake: Handshake,
+    act_three: &[u8],
+) -> Result<(PublicKey, Transport), P2PError> {
+    let (encrypted_key, tag) = read_act(act_three, PUBLIC_KEY_LEN + TAG_LEN)?;
+    let remote_key = handshake.decrypt_and_hash(1, encrypted_key)?;
+    let remote_key =
+        PublicKey::from_slice(&remote_key).map_err(|err| P2PError::Decode(Box::new(err)))?;
+    handshake.mix_key(&ecdh(&remote_key, &handshake.ephemeral_key));
+    handshake.decrypt_and_hash(0, tag)?;
+    Ok((remote_key, handshake.split(false)))
+}
+
+/// Encrypts and decrypts the messages once the handshake is finished.
+pub struct Transport {
+    sending: CipherState,
+    receiving: CipherState,
+    /// The length of the message being received, once its prefix was decrypted.
+    body_len: Option<usize>,
+}
+
+impl Transport {
+    /// Encrypts the message, prefixed by its encrypted length.
+    pub fn encrypt(&mut self, message: &[u8]) -> Vec<u8> {
+        let mut packet = self.sending.encrypt(&(message.len() as u16).to_be_bytes());
+        packet.extend(self.sending.encrypt(message));
+        packet
+    }
+
+    /// Takes the next message from the buffer, or nothing if more data is needed.
+    pub fn decrypt(&mut self, buffer: &mut BytesMut) -> Result<Option<Vec<u8>>, P2PError> {
+        let body_len = match self.body_len {
+            Some(body_len) => body_len,
+            None if buffer.len() < 2 + TAG_LEN => return Ok(None),
+            None => {
+                let len = self.receiving.decrypt(&buffer.split_to(2 + TAG_LEN))?;
+                let body_len = u16::from_be_bytes([len[0], len[1]]) as usize + TAG_LEN;
+                self.body_len = Some(body_len);
+                body_len
+            }
+        };
+        if buffer.len() < body_len {
+            return Ok(None);
+        }
+        self.body_len = None;
+        self.receiving.decrypt(&buffer.split_to(body_len)).map(Some)
+    }
+}
+
+/// The key and nonce of a single direction of the transport.
+struct CipherState {
+    k: [u8; 32],
+    n: u64,
+    ck: [u8; 32],
+}
+
+impl CipherState {
+    fn new(k: [u8; 32], ck: [u8; 32]) -> CipherState {
+        CipherState { k, n: 0, ck }
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = encrypt(&self.k, self.n, &[], plaintext);
+        self.next_nonce();
+        ciphertext
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, P2PError> {
+        let plaintext = decrypt(&self.k, self.n, &[], ciphertext)?;
+        self.next_nonce();
+        Ok(plaintext)
+    }
+
+    fn next_nonce(&mut self) {
+        self.n += 1;
+        if self.n == KEY_ROTATION {
+            (self.ck, self.k) = hkdf(&self.ck, &self.k);
+            self.n = 0;
+        }
+    }
+}
+
+fn act(parts: &[&[u8]]) -> Vec<u8> {
+    let mut act = vec![HANDSHAKE_VERSION];
+    for part in parts {
+        act.extend_from_slice(part);
+    }
+    act
+}
+
+/// Splits an act in its key, or encrypted key, and its tag.
+fn read_act(act: &[u8], key_len: usize) -> Result<(&[u8], &[u8]), P2PError> {
+    if act.len() != 1 + key_len + TAG_LEN {
+        return Err(P2PError::Decode(
+            format!(
+                "act of {} bytes instead of {}",
+                act.len(),
+                1 + key_len + TAG_LEN
+            )
+            .into(),
+        ));
+    }
+    if act[0] != HANDSHAKE_VERSION {
+        return Err(P2PError::ProtocolViolation(format!(
+            "unknown handshake version {}",
+            act[0]
+        )));
+    }
+    Ok(act[1..].split_at(key_len))
+}
+
+fn encrypt(key: &[u8; 32], nonce: u64, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(
+            &chacha_nonce(nonce).into(),
+            Payload {
+                msg: plaintext,
+                aad: ad,
+            },
+        )
+        .expect("plain texts are way shorter than the ChaChaPoly limit")
+}
+
+fn decrypt(key: &[u8; 32], nonce: u64, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, P2PError> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(
+            &chacha_nonce(nonce).into(),
+            Payload {
+                msg: ciphertext,
+                aad: ad,
+            },
+        )
+        .map_err(|_| P2PError::Decode("message authentication failed".into()))
+}
+
+/// The 96 bits nonce: 32 zero bits followed by the little endian counter.
+fn chacha_nonce(nonce: u64) -> [u8; 12] {
+    let mut chacha_nonce = [0; 12];
+    chacha_nonce[4..].copy_from_slice(&nonce.to_le_bytes());
+    chacha_nonce
+}
+
+fn hkdf(salt: &[u8; 32], ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0; 64];
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(&[], &mut okm)
+        .expect("64 bytes is a valid HKDF-SHA256 length");
+    let (first, second) = okm.split_at(32);
+    (
+        first.try_into().expect("32 bytes"),
+        second.try_into().expect("32 bytes"),
+    )
+}
+
+/// The SHA256 of the compressed shared point, as the secp256k1 ECDH does.
+fn ecdh(public_key: &PublicKey, secret_key: &SecretKey) -> [u8; 32] {
+    SharedSecret::new(public_key, secret_key).secret_bytes()
+}
+
+fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret_key(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    /// The test vectors of the BOLT 8 appendix.
+    #[test]
+    fn handshake_follows_the_bolt8_test_vectors() {
+        let secp = Secp256k1::new();
+        let (initiator_key, responder_key) = (secret_key(0x11), secret_key(0x21));
+
+        let (initiator, act_one) = act_one(
+            &secp,
+            &PublicKey::from_secret_key(&secp, &responder_key),
+            secret_key(0x12),
+        );
+        assert_eq!(
+            "00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df6086551151f58b8afe6c195782c6a",
+            hex::encode(&act_one)
+        );
+
+        let (responder, act_two) =
+            act_two(&secp, &responder_key, secret_key(0x22), &act_one).unwrap();
+        assert_eq!(
+            "0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730ae",
+            hex::encode(&act_two)
+        );
+
+        let (act_three, mut initiator) =
+            act_three(initiator, &secp, &initiator_key, &act_two).unwrap();
+        assert_eq!(
+            "00b9e3a702e93e3a9948c2ed6e5fd7590a6e1c3a0344cfc9d5b57357049aa22355361aa02e55a8fc28fef5bd6d71ad0c38228dc68b1c466263b47fdf31e560e139ba",
+            hex::encode(&act_three)
+        );
+        assert_eq!(
+            "969ab31b4d288cedf6218839b27a3e2140827047f2c0f01bf5c04435d43511a9",
+            hex::encode(initiator.sending.k)
+        );
+        assert_eq!(
+            "bb9020b8965f4df047e07f955f3c4b88418984aadc5cdb35096b9ea8fa5c3442",
+            hex::encode(initiator.receiving.k)
+        );
+
+        let (remote_key, mut responder) = read_act_three(responder, &act_three).unwrap();
+        assert_eq!(
+            PublicKey::from_secret_key(&secp, &initiator_key),
+            remote_key
+        );
+
+        // Messages are decrypted once complete, across key rotations.
+        for i in 0..1001 {
+            let mut buffer = BytesMut::from(&initiator.encrypt(b"hello")[..]);
+            if i == 0 {
+                assert_eq!(
+                    "cf2b30ddf0cf3f80e7c35a6e6730b59fe802473180f396d88a8fb0db8cbcf25d2f214cf9ea1d95",
+                    hex::encode(&buffer)
+                );
+            }
+            let last = buffer.split_off(buffer.len() - 1);
+            assert_eq!(None, responder.decrypt(&mut buffer).unwrap());
+            buffer.extend_from_slice(&last);
+            assert_eq!(
+                b"hello".to_vec(),
+                responder.decrypt(&mut buffer).unwrap().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn act_three_rejects_unverifiable_act_two() {
+        let secp = Secp256k1::new();
+        let responder_key = secret_key(0x21);
+        let (initiator, mut act_one) = act_one(
+            &secp,
+            &PublicKey::from_secret_key(&secp, &responder_key),
+            secret_key(0x12),
+        );
+        let (_, mut act_two) = act_two(&secp, &responder_key, secret_key(0x22), &act_one).unwrap();
+        act_two[ACT_TWO_LEN - 1] ^= 1;
+        act_one[ACT_ONE_LEN - 1] ^= 1;
+
+        assert!(matches!(
+            act_three(initiator, &secp, &secret_key(0x11), &act_two),
+            Err(P2PError::AuthenticationFailed(_))
+        ));
+        assert!(super::act_two(&secp, &responder_key, secret_key(0x22), &act_one).is_err());
+    }
+}
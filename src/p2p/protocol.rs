@@ -0,0 +1,847 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    join,
+    net::{
+        lookup_host,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    select, signal,
+    sync::{
+        broadcast,
+        mpsc::{self, UnboundedSender},
+        oneshot,
+    },
+    time::{self, Instant},
+};
+
+use super::{
+    proxy::{self, Proxy},
+    view::{Event, EventChain, EventDirection, HandshakeResult},
+    P2PError, Phase,
+};
+
+/// A p2p handshake protocol. Implementations own the message framing, the handshake
+/// state machine and the emission of the message events, while [run] provides the
+/// connection, timeouts, shutdown and [EventChain] plumbing shared by all of them.
+///
+/// All the protocol methods are called from the same task, one at a time, so
+/// messages are always handled in the same order they arrive.
+pub trait Protocol: Send + 'static {
+    /// The port used when the node address does not contain one.
+    fn default_port(&self) -> u16;
+
+    /// The address to connect to for the provided target. Protocols whose targets
+    /// carry more than the node address, like its identity, extract it here.
+    fn node_addr<'a>(&'a self, target: &'a str) -> &'a str {
+        target
+    }
+
+    /// Starts the handshake once connected, usually by sending the first message.
+    fn start(&mut self, session: &mut Session) -> Result<(), P2PError>;
+
+    /// Handles the data received from the peer. Implementations must consume the
+    /// bytes of the complete messages from the buffer, leaving the incomplete ones
+    /// there until more data is received.
+    fn on_data(&mut self, buffer: &mut BytesMut, session: &mut Session) -> Result<(), P2PError>;
+
+    /// Whether the handshake was successfully completed. The connection is closed
+    /// as soon as it is, unless there is a [Protocol::follow_up].
+    fn is_complete(&self) -> bool;
+
+    /// The time the connection is kept open once the handshake is complete, for the
+    /// exchanges following it, like measuring the latency of the peer. There are none
+    /// by default.
+    fn follow_up(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Whether the exchanges following the handshake are done, so the connection can
+    /// be closed before the follow up time is over.
+    fn is_followed_up(&self) -> bool {
+        true
+    }
+
+    /// Called once the follow up is over, because it is done, its time ran out or the
+    /// connection failed, for publishing its outcome. The handshake is already complete
+    /// at this point, so the follow up failures do not affect its result.
+    fn end_follow_up(&mut self, _session: &mut Session) -> Result<(), P2PError> {
+        Ok(())
+    }
+
+    /// The error reported when the peer closes the connection before the handshake is
+    /// complete. Protocols whose peers close the connection instead of answering
+    /// certain failures, like authentication ones, can tell them apart here.
+    fn on_closed(&mut self, source: Option<io::Error>) -> P2PError {
+        P2PError::PeerClosed(source)
+    }
+
+    /// Whether the failed handshake should be retried over a new connection, like when
+    /// falling back to an older version of the protocol. Implementations prepare their
+    /// state for the new attempt before accepting it. Timed out handshakes are never retried.
+    fn retry(&mut self, _err: &P2PError) -> bool {
+        false
+    }
+}
+
+/// The handle protocols use for talking with the peer and publishing events.
+pub struct Session {
+    peer_addr: SocketAddr,
+    frames: UnboundedSender<Frame>,
+    events: UnboundedSender<Event>,
+}
+
+impl Session {
+    /// The address of the connected peer. It is unspecified when the address was
+    /// resolved by the proxy, like for onion services.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Queues the bytes of a message for being sent to the peer. Its event is
+    /// published once written, so it reflects when the message actually left.
+    pub fn send(&mut self, event: Event, bytes: Vec<u8>) -> Result<(), P2PError> {
+        self.frames
+            .send(Frame { event, bytes })
+            .map_err(|_| P2PError::internal("message channel closed", None))
+    }
+
+    /// Publishes an event that does not belong to an outgoing message, like
+    /// the received ones.
+    pub fn publish(&mut self, event: Event) -> Result<(), P2PError> {
+        Ok(self.events.send(event)?)
+    }
+}
+
+/// The bytes of an outgoing message along with its event.
+struct Frame {
+    event: Event,
+    bytes: Vec<u8>,
+}
+
+/// The time limits of a single handshake.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Maximum time for resolving the address, and then for each connection attempt.
+    /// Every resolved address gets its own one, so the connection phase may take up
+    /// to one per address tried.
+    pub connect: Duration,
+    /// Maximum time for the protocol handshake once connected.
+    pub handshake: Duration,
+    /// The moment at which the handshake is timed out, whatever its phase is.
+    pub deadline: Option<Instant>,
+}
+
+impl Timeouts {
+    /// The moment at which a phase starting now with the provided timeout
+    /// should be finished.
+    fn phase_deadline(&self, timeout: Duration) -> Instant {
+        let phase_deadline = Instant::now() + timeout;
+        match self.deadline {
+            Some(deadline) => phase_deadline.min(deadline),
+            None => phase_deadline,
+        }
+    }
+}
+
+/// Performs the handshake of the provided protocol with the node. The result contains
+/// all the events collected until the handshake finished or failed, including the ones
+/// of the previous connections when the protocol asked for retrying it.
+pub async fn run<P: Protocol>(
+    node_addr: String,
+    timeouts: Timeouts,
+    protocol: P,
+) -> HandshakeResult {
+    run_through(node_addr, timeouts, None, protocol).await
+}
+
+/// Like [run], but connecting to the node through the provided proxy, if any.
+pub async fn run_through<P: Protocol>(
+    node_addr: String,
+    timeouts: Timeouts,
+    proxy: Option<Arc<Proxy>>,
+    protocol: P,
+) -> HandshakeResult {
+    let mut protocol = protocol;
+    let mut events = Vec::new();
+    loop {
+        let (event_chain, error, returned_protocol) =
+            attempt(&node_addr, &timeouts, proxy.as_deref(), protocol, events).await;
+        let err = match error {
+            Some(err) if !matches!(err, P2PError::Timeout(_)) => err,
+            error => return HandshakeResult::new(event_chain, error),
+        };
+        protocol = match returned_protocol {
+            Some(mut protocol) => match protocol.retry(&err) {
+                true => protocol,
+                false => return HandshakeResult::new(event_chain, Some(err)),
+            },
+            None => return HandshakeResult::new(event_chain, Some(err)),
+        };
+        events = event_chain.into_events();
+        let mut retry_event = Event::new("retry".to_string(), EventDirection::INTERNAL);
+        retry_event.set_pair("reason".to_string(), err.kind().to_string());
+        events.push(retry_event);
+    }
+}
+
+/// Performs a single handshake attempt over a new connection, after the events of the
+/// previous attempts. The protocol is returned along the result, unless its task failed.
+async fn attempt<P: Protocol>(
+    node_addr: &str,
+    timeouts: &Timeouts,
+    proxy: Option<&Proxy>,
+    protocol: P,
+    previous_events: Vec<Event>,
+) -> (EventChain, Option<P2PError>, Option<P>) {
+    let event_chain_id = node_addr.to_string();
+
+    // Resolve the node address and stablish the TCP connection with timeout.
+    let mut lifecycle_events = previous_events;
+    let connection = connect_node(
+        protocol.node_addr(node_addr),
+        protocol.default_port(),
+        timeouts,
+        proxy,
+        &mut lifecycle_events,
+    )
+    .await;
+    let (stream, peer_addr) = match connection {
+        Ok(connection) => connection,
+        Err(err) => {
+            let mut event_chain = EventChain::new(event_chain_id);
+            lifecycle_events
+                .into_iter()
+                .for_each(|ev| event_chain.add(ev));
+            return (event_chain, Some(err), Some(protocol));
+        }
+    };
+    let handshake_deadline = timeouts.phase_deadline(timeouts.handshake);
+
+    // Setup shutdown broadcast channels
+    let (shutdown_tx, _) = broadcast::channel::<usize>(1);
+    let mut ext_shutdown_shutdown_rx = shutdown_tx.subscribe();
+
+    // Spawn the event chain task. It ends once all the event publishers are gone, so
+    // it always returns all the collected events, even if the handshake fails at some point.
+    let (ev_tx, mut ev_rx) = mpsc::unbounded_channel::<Event>();
+    let ev_chain_id = event_chain_id.clone();
+    let event_chain_handle = tokio::spawn(async move {
+        let mut event_chain = EventChain::new(ev_chain_id);
+        if let Some(peer_addr) = peer_addr {
+            event_chain.set_peer_addr(peer_addr);
+        }
+        lifecycle_events
+            .into_iter()
+            .for_each(|ev| event_chain.add(ev));
+        while let Some(ev) = ev_rx.recv().await {
+            event_chain.add(ev);
+        }
+        event_chain
+    });
+
+    let (rx_stream, mut tx_stream) = stream.into_split();
+
+    // Spawn the message writer task. This will take care of writing all the messages to the socket.
+    let (frame_tx, mut frame_rx) = mpsc::unbounded_channel::<Frame>();
+    let frame_writer_ev_tx = ev_tx.clone();
+    let mut frame_writer_shutdown_rx = shutdown_tx.subscribe();
+    let frame_writer_shutdown_tx = shutdown_tx.clone();
+    let frame_writer_handle = tokio::spawn(async move {
+        loop {
+            select! {
+                Some(frame) = frame_rx.recv() => {
+                    if let Err(err) = write_frame(&mut tx_stream, frame, &frame_writer_ev_tx).await {
+                        // Stop the rest of the tasks, the handshake cannot progress anymore.
+                        let _ = frame_writer_shutdown_tx.send(1);
+                        return Err(err);
+                    }
+                }
+                result = frame_writer_shutdown_rx.recv() => {
+                    // Messages queued before the shutdown, like the last handshake answer, are still sent.
+                    while let Ok(frame) = frame_rx.try_recv() {
+                        write_frame(&mut tx_stream, frame, &frame_writer_ev_tx).await?;
+                    }
+                    tx_stream.shutdown().await?;
+                    return match result {
+                        Ok(_) => Ok(()),
+                        Err(err) => Err(P2PError::from(err)),
+                    }
+                }
+            }
+        }
+    });
+
+    // Spawn the message reader task. It drives the protocol, so received data is handled
+    // in the same order it arrives. It returns the protocol along whether the handshake
+    // was completed.
+    let session = Session {
+        peer_addr: peer_addr.unwrap_or_else(|| (Ipv4Addr::UNSPECIFIED, 0).into()),
+        frames: frame_tx,
+        events: ev_tx,
+    };
+    let reader_shutdown_rx = shutdown_tx.subscribe();
+    let reader_shutdown_tx = shutdown_tx.clone();
+    let (complete_tx, complete_rx) = oneshot::channel();
+    let follow_up_timeouts = *timeouts;
+    let reader_handle = tokio::spawn(async move {
+        let mut protocol = protocol;
+        let progress = read_frames(
+            &mut protocol,
+            session,
+            rx_stream,
+            reader_shutdown_rx,
+            reader_shutdown_tx,
+            complete_tx,
+            follow_up_timeouts,
+        )
+        .await;
+        (protocol, progress)
+    });
+
+    // Wait for external shutdown signals ctr+c ...
+    let mut timed_out = false;
+    select! {
+        _ = time::sleep_until(handshake_deadline) => {
+            timed_out = true;
+            let _ = shutdown_tx.send(1);
+        }
+        // Once complete, the handshake cannot time out anymore, while the follow up
+        // has its own time limit.
+        Ok(()) = complete_rx => {
+            select! {
+                val = signal::ctrl_c() => {
+                    if val.is_ok() {
+                        let _ = shutdown_tx.send(1);
+                    }
+                }
+                _val = ext_shutdown_shutdown_rx.recv() => {}
+            }
+        }
+        val = signal::ctrl_c() => {
+            if val.is_ok(){
+                let _ = shutdown_tx.send(1);
+            }
+        }
+        // Break this select! once an internal shutdown is invoked from any of the subs systems.
+        _val = ext_shutdown_shutdown_rx.recv()=>{}
+    }
+
+    let (event_chain_res, frame_writer_res, reader_res) =
+        join!(event_chain_handle, frame_writer_handle, reader_handle);
+    let (protocol, reader_res) = match reader_res {
+        Ok((protocol, progress)) => (Some(protocol), Ok(progress)),
+        Err(err) => (None, Err(err)),
+    };
+    let mut event_chain = match event_chain_res {
+        Ok(event_chain) => event_chain,
+        Err(err) => return (EventChain::new(event_chain_id), Some(err.into()), protocol),
+    };
+    event_chain.add(Event::new("closed".to_string(), EventDirection::INTERNAL));
+    if let Ok(Ok(true)) = reader_res {
+        event_chain.mark_as_complete();
+    }
+
+    // Report the first error that happened in the message reader or writer, if any,
+    // along with all the events collected until that moment.
+    let error = [reader_res.map(|res| res.map(|_| ())), frame_writer_res]
+        .into_iter()
+        .find_map(|res| match res {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err),
+            Err(err) => Some(P2PError::from(err)),
+        })
+        .or_else(|| {
+            (timed_out && !event_chain.is_complete()).then_some(P2PError::Timeout(Phase::Handshake))
+        });
+    (event_chain, error, protocol)
+}
+
+/// Drives the protocol with the data received from the peer, until the handshake is
+/// completed, it fails or a shutdown is requested. Returns whether the handshake was
+/// completed, which is also notified as soon as it happens, as the protocol follow up
+/// runs after it. The rest of the tasks are stopped once the reading is over.
+async fn read_frames<P: Protocol>(
+    protocol: &mut P,
+    mut session: Session,
+    mut stream: OwnedReadHalf,
+    mut shutdown_rx: broadcast::Receiver<usize>,
+    shutdown_tx: broadcast::Sender<usize>,
+    complete_tx: oneshot::Sender<()>,
+    timeouts: Timeouts,
+) -> Result<bool, P2PError> {
+    // A complete handshake usually takes a few hundred bytes. We allocate much more
+    // so we don't need to do more allocations.
+    let mut buffer = BytesMut::with_capacity(1024);
+    let mut first_byte = true;
+
+    let mut progress = protocol.start(&mut session);
+    loop {
+        match progress {
+            Ok(()) if protocol.is_complete() => break,
+            Ok(()) => {}
+            Err(err) => {
+                let _ = shutdown_tx.send(1);
+                return Err(err);
+            }
+        }
+        select! {
+            // Once a shutdown is requested, reading errors are not relevant anymore.
+            biased;
+            result = shutdown_rx.recv() => {
+                return match result {
+                    Ok(_) => Ok(false),
+                    Err(err) => Err(P2PError::from(err)),
+                }
+            }
+            read_res = stream.read_buf(&mut buffer) => {
+                progress = match read_res {
+                    Ok(0) => Err(protocol.on_closed(None)),
+                    Ok(_) if first_byte => {
+                        first_byte = false;
+                        session
+                            .publish(Event::new("first-byte".to_string(), EventDirection::INTERNAL))
+                            .and_then(|_| protocol.on_data(&mut buffer, &mut session))
+                    }
+                    Ok(_) => protocol.on_data(&mut buffer, &mut session),
+                    Err(err) => match P2PError::from(err) {
+                        P2PError::PeerClosed(source) => Err(protocol.on_closed(source)),
+                        err => Err(err),
+                    },
+                };
+            },
+        }
+    }
+
+    let _ = complete_tx.send(());
+    if let Some(follow_up) = protocol.follow_up() {
+        let deadline = timeouts.phase_deadline(follow_up);
+        while !protocol.is_followed_up() {
+            select! {
+                biased;
+                _ = shutdown_rx.recv() => break,
+                _ = time::sleep_until(deadline) => break,
+                read_res = stream.read_buf(&mut buffer) => match read_res {
+                    Ok(1..) if protocol.on_data(&mut buffer, &mut session).is_ok() => {}
+                    _ => break,
+                },
+            }
+        }
+        let _ = protocol.end_follow_up(&mut session);
+    }
+    let _ = shutdown_tx.send(1);
+    Ok(true)
+}
+
+/// Writes the frame bytes to the peer, publishing its event once written.
+async fn write_frame(
+    stream: &mut OwnedWriteHalf,
+    frame: Frame,
+    event_publisher: &UnboundedSender<Event>,
+) -> Result<(), P2PError> {
+    stream.write_all(frame.bytes.as_slice()).await?;
+    event_publisher.send(frame.event)?;
+    Ok(())
+}
+
+/// Resolves the node address and connects to it, recording the lifecycle events
+/// of both phases in the provided vector. The peer address is not known when the
+/// proxy resolves the node address, as there is no resolution phase then.
+async fn connect_node(
+    node_addr: &str,
+    default_port: u16,
+    timeouts: &Timeouts,
+    proxy: Option<&Proxy>,
+    events: &mut Vec<Event>,
+) -> Result<(TcpStream, Option<SocketAddr>), P2PError> {
+    let (host, port) = parse_node_addr(node_addr, default_port)?;
+    if proxy::is_onion(&host) {
+        proxy::check_onion(&host).map_err(|reason| P2PError::InvalidTarget {
+            target: node_addr.to_string(),
+            reason,
+        })?;
+    }
+
+    match proxy {
+        Some(proxy) if proxy.resolves(&host) => {
+            let mut connect_event = Event::new("connect".to_string(), EventDirection::INTERNAL);
+            connect_event.set_pair("addr".to_string(), format!("{}:{}", host, port));
+            connect_event.set_pair("proxy".to_string(), proxy.to_string());
+            events.push(connect_event);
+
+            let deadline = timeouts.phase_deadline(timeouts.connect);
+            let stream = time::timeout_at(deadline, proxy.connect(&host, port))
+                .await
+                .map_err(|_| P2PError::Timeout(Phase::Connect))??;
+            return Ok((stream, None));
+        }
+        None if proxy::is_onion(&host) => {
+            return Err(P2PError::InvalidTarget {
+                target: node_addr.to_string(),
+                reason: "onion addresses can only be reached through a proxy, like the Tor one"
+                    .to_string(),
+            })
+        }
+        _ => {}
+    }
+
+    let mut resolve_event = Event::new("resolve".to_string(), EventDirection::INTERNAL);
+    let peer_addrs = time::timeout_at(
+        timeouts.phase_deadline(timeouts.connect),
+        resolve(&host, port),
+    )
+    .await;
+    if let Ok(Ok(peer_addrs)) = &peer_addrs {
+        resolve_event.set_pair("addrs".to_string(), peer_addrs.len() as u64);
+    }
+    events.push(resolve_event);
+    let peer_addrs = peer_addrs.map_err(|_| P2PError::Timeout(Phase::Resolve))??;
+
+    let (stream, peer_addr) = connect(&peer_addrs, timeouts, proxy, events).await?;
+    Ok((stream, Some(peer_addr)))
+}
+
+/// Splits a node address in its host and port parts. Hostnames, IPv4 and IPv6
+/// literals (bracketed when a port is given) are accepted. The provided default
+/// port is used when the address does not contain one.
+pub(crate) fn parse_node_addr(
+    node_addr: &str,
+    default_port: u16,
+) -> Result<(String, u16), P2PError> {
+    let invalid_addr = || P2PError::ResolveFailed {
+        target: node_addr.to_string(),
+        source: io::Error::new(io::ErrorKind::InvalidInput, "invalid node address"),
+    };
+
+    if let Ok(ip) = node_addr.parse::<Ipv6Addr>() {
+        return Ok((ip.to_string(), default_port));
+    }
+
+    let (host, port) = match node_addr.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']').ok_or_else(invalid_addr)?;
+            host.parse::<Ipv6Addr>().map_err(|_| invalid_addr())?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid_addr)?)),
+            }
+        }
+        None => match node_addr.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (node_addr, None),
+        },
+    };
+
+    if host.is_empty() {
+        return Err(invalid_addr());
+    }
+    let port = match port {
+        Some(port) => port.parse::<u16>().map_err(|_| invalid_addr())?,
+        None => default_port,
+    };
+    Ok((host.to_string(), port))
+}
+
+/// Resolves all the A/AAAA records of the provided host.
+async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>, P2PError> {
+    let resolve_error = |source: io::Error| P2PError::ResolveFailed {
+        target: host.to_string(),
+        source,
+    };
+    let addrs: Vec<SocketAddr> = lookup_host((host, port))
+        .await
+        .map_err(resolve_error)?
+        .collect();
+    if addrs.is_empty() {
+        return Err(resolve_error(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no addresses found",
+        )));
+    }
+    Ok(addrs)
+}
+
+/// Tries to connect to each one of the provided addresses in order, directly or through
+/// the proxy, returning the first established connection along its address or the last
+/// error if none succeeded.
+async fn connect(
+    addrs: &[SocketAddr],
+    timeouts: &Timeouts,
+    proxy: Option<&Proxy>,
+    events: &mut Vec<Event>,
+) -> Result<(TcpStream, SocketAddr), P2PError> {
+    let mut last_err = None;
+    for addr in addrs {
+        let mut connect_event = Event::new("connect".to_string(), EventDirection::INTERNAL);
+        connect_event.set_pair("addr".to_string(), addr.to_string());
+        if let Some(proxy) = proxy {
+            connect_event.set_pair("proxy".to_string(), proxy.to_string());
+        }
+        events.push(connect_event);
+
+        let connection = async {
+            match proxy {
+                Some(proxy) => proxy.connect(&addr.ip().to_string(), addr.port()).await,
+                None => TcpStream::connect(addr)
+                    .await
+                    .map_err(|source| P2PError::ConnectFailed {
+                        addr: *addr,
+                        source,
+                    }),
+            }
+        };
+        // Each address has its own timeout, so an unreachable one does not leave the
+        // next ones without time.
+        let deadline = timeouts.phase_deadline(timeouts.connect);
+        match time::timeout_at(deadline, connection).await {
+            Ok(Ok(stream)) => return Ok((stream, *addr)),
+            Ok(Err(err)) => last_err = Some(err),
+            Err(_) => last_err = Some(P2PError::Timeout(Phase::Connect)),
+        }
+    }
+    Err(last_err.expect("at least one resolved address"))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::future::Future;
+
+    use tokio::{net::TcpListener, task::JoinHandle};
+
+    use super::*;
+
+    /// The time limits of the handshakes run by the tests, which only reach local peers.
+    pub(crate) const TIMEOUTS: Timeouts = Timeouts {
+        connect: Duration::from_millis(500),
+        handshake: Duration::from_millis(500),
+        deadline: None,
+    };
+
+    /// Starts a peer on a local port, serving the first connection it accepts with the
+    /// provided function. It returns the address of the peer and the task serving it.
+    pub(crate) async fn peer<F, T>(
+        serve: impl FnOnce(TcpStream) -> F + Send + 'static,
+    ) -> (SocketAddr, JoinHandle<T>)
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream).await
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn parse_node_addr_accepts_hostnames_and_ip_literals() {
+        let cases = [
+            ("192.168.1.1:8333", ("192.168.1.1", 8333)),
+            ("192.168.1.1", ("192.168.1.1", 18333)),
+            ("seed.bitcoin.sipa.be:8333", ("seed.bitcoin.sipa.be", 8333)),
+            ("seed.bitcoin.sipa.be", ("seed.bitcoin.sipa.be", 18333)),
+            ("[2001:db8::1]:8333", ("2001:db8::1", 8333)),
+            ("[2001:db8::1]", ("2001:db8::1", 18333)),
+            ("2001:db8::1", ("2001:db8::1", 18333)),
+        ];
+        for (node_addr, (host, port)) in cases {
+            assert_eq!(
+                (host.to_string(), port),
+                parse_node_addr(node_addr, 18333).unwrap(),
+                "parsing {}",
+                node_addr
+            );
+        }
+    }
+
+    #[test]
+    fn parse_node_addr_rejects_invalid_addresses() {
+        for node_addr in [
+            "",
+            ":8333",
+            "host:port",
+            "host:99999",
+            "[2001:db8::1",
+            "[2001:db8::1]8333",
+            "[not-ipv6]:8333",
+        ] {
+            assert!(
+                matches!(
+                    parse_node_addr(node_addr, 8333),
+                    Err(P2PError::ResolveFailed { .. })
+                ),
+                "parsing {}",
+                node_addr
+            );
+        }
+    }
+
+    #[test]
+    fn phase_deadline_is_capped_by_the_global_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let mut timeouts = Timeouts {
+            deadline: Some(deadline),
+            ..TIMEOUTS
+        };
+
+        assert_eq!(deadline, timeouts.phase_deadline(timeouts.connect));
+
+        timeouts.deadline = None;
+        assert!(timeouts.phase_deadline(timeouts.connect) > deadline);
+    }
+
+    /// Echoes a fixed greeting and completes once the peer answers with the same one.
+    struct Greeting {
+        received: bool,
+    }
+
+    impl Protocol for Greeting {
+        fn default_port(&self) -> u16 {
+            7
+        }
+
+        fn start(&mut self, session: &mut Session) -> Result<(), P2PError> {
+            session.send(
+                Event::new("hello".to_string(), EventDirection::OUT),
+                b"hello".to_vec(),
+            )
+        }
+
+        fn on_data(
+            &mut self,
+            buffer: &mut BytesMut,
+            session: &mut Session,
+        ) -> Result<(), P2PError> {
+            if buffer.len() < 5 {
+                return Ok(());
+            }
+            let greeting = buffer.split_to(5);
+            if greeting.as_ref() != b"hello" {
+                return Err(P2PError::ProtocolViolation(
+                    "unexpected greeting".to_string(),
+                ));
+            }
+            self.received = true;
+            session.publish(Event::new("hello".to_string(), EventDirection::IN))
+        }
+
+        fn is_complete(&self) -> bool {
+            self.received
+        }
+    }
+
+    async fn echo_server() -> SocketAddr {
+        let (addr, _) = peer(|mut stream| async move {
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            time::sleep(Duration::from_millis(10)).await;
+            // Split the answer, so the protocol needs to wait for more data.
+            stream.write_all(&buf[..2]).await.unwrap();
+            stream.flush().await.unwrap();
+            time::sleep(Duration::from_millis(10)).await;
+            stream.write_all(&buf[2..]).await.unwrap();
+            let _ = stream.read(&mut buf).await;
+        })
+        .await;
+        addr
+    }
+
+    #[tokio::test]
+    async fn run_drives_the_protocol_until_complete() {
+        let addr = echo_server().await;
+
+        let result = run(addr.to_string(), TIMEOUTS, Greeting { received: false }).await;
+
+        let event_chain = result.result().unwrap();
+        assert!(event_chain.is_complete());
+        let events: Vec<&str> = event_chain.events().iter().map(|ev| ev.name()).collect();
+        assert_eq!(
+            vec![
+                "resolve",
+                "connect",
+                "hello",
+                "first-byte",
+                "hello",
+                "closed"
+            ],
+            events
+        );
+    }
+
+    #[tokio::test]
+    async fn run_through_connects_via_the_proxy() {
+        let addr = echo_server().await;
+        let (proxy_addr, proxy_server) = proxy::tests::socks5_server(None).await;
+        let proxy = format!("socks5://{}", proxy_addr).parse().unwrap();
+
+        let result = run_through(
+            addr.to_string(),
+            TIMEOUTS,
+            Some(Arc::new(proxy)),
+            Greeting { received: false },
+        )
+        .await;
+
+        let event_chain = result.result().unwrap();
+        assert!(event_chain.is_complete());
+        assert_eq!(Some(addr), event_chain.peer_addr());
+        let connect = &event_chain.events()[1];
+        assert_eq!("connect", connect.name());
+        assert!(connect
+            .data_pairs()
+            .contains(&("proxy".to_string(), proxy_addr.to_string().into())));
+        assert_eq!(addr.to_string(), proxy_server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn run_through_lets_the_proxy_resolve_onion_addresses() {
+        let (proxy_addr, proxy_server) = proxy::tests::socks5_server(None).await;
+        let proxy = format!("socks5://{}", proxy_addr).parse().unwrap();
+
+        let result = run_through(
+            proxy::tests::ONION.to_string(),
+            TIMEOUTS,
+            Some(Arc::new(proxy)),
+            Greeting { received: false },
+        )
+        .await;
+
+        let event_chain = result.result().unwrap();
+        assert!(event_chain.is_complete());
+        assert_eq!(None, event_chain.peer_addr());
+        let events: Vec<&str> = event_chain.events().iter().map(|ev| ev.name()).collect();
+        assert_eq!(
+            vec!["connect", "hello", "first-byte", "hello", "closed"],
+            events
+        );
+        assert_eq!(
+            format!("{}:7", proxy::tests::ONION),
+            proxy_server.await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn run_rejects_onion_addresses_without_proxy() {
+        let result = run(
+            proxy::tests::ONION.to_string(),
+            TIMEOUTS,
+            Greeting { received: false },
+        )
+        .await;
+
+        assert!(matches!(
+            result.error(),
+            Some(P2PError::InvalidTarget { .. })
+        ));
+    }
+}
@@ -0,0 +1,452 @@
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+};
+
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+use super::{
+    config::Commands,
+    view::{Event, EventDirection, HandshakeResult, Value, EMOJI_SUCCESS, EMOJI_WARNING},
+};
+
+/// The reports built from the results of all the handshakes of a command, which
+/// are shown once all of them finished.
+#[derive(Default, Serialize)]
+pub struct Summary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    external_addr: Option<ExternalAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tip_lag: Option<TipLag>,
+}
+
+impl Summary {
+    /// Builds the summary with the reports requested by the command options.
+    pub fn new(commands: &Commands) -> Summary {
+        match commands {
+            Commands::Btc {
+                external_addr,
+                tip_lag,
+                ..
+            } => Summary {
+                external_addr: external_addr.then(ExternalAddr::default),
+                tip_lag: tip_lag.map(TipLag::new),
+            },
+            _ => Summary::default(),
+        }
+    }
+
+    pub fn add(&mut self, result: &HandshakeResult) {
+        if let Some(external_addr) = self.external_addr.as_mut() {
+            external_addr.add(result);
+        }
+        if let Some(tip_lag) = self.tip_lag.as_mut() {
+            tip_lag.add(result);
+        }
+    }
+
+    /// Whether no report was requested, so there is nothing to show.
+    pub fn is_empty(&self) -> bool {
+        self.external_addr.is_none() && self.tip_lag.is_none()
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut reports: Vec<String> = Vec::new();
+        if let Some(external_addr) = &self.external_addr {
+            reports.push(external_addr.to_string());
+        }
+        if let Some(tip_lag) = &self.tip_lag {
+            reports.push(tip_lag.to_string());
+        }
+        write!(f, "{}", reports.join("\n"))
+    }
+}
+
+/// Our external address as seen by the peers, taken from the receiver of their
+/// version messages. Disagreeing peers point to a NAT or an egress that does not
+/// use a single address.
+#[derive(Default)]
+pub struct ExternalAddr {
+    peers: usize,
+    ips: Vec<ObservedIp>,
+}
+
+/// An external IP along the ports peers saw it with.
+#[derive(Serialize)]
+struct ObservedIp {
+    ip: IpAddr,
+    peers: usize,
+    ports: Vec<u16>,
+}
+
+impl ExternalAddr {
+    pub fn add(&mut self, result: &HandshakeResult) {
+        let receiver = peer_version(result).and_then(|ev| ev.pair("receiver"));
+        let addr = match receiver {
+            Some(Value::Text(addr)) => addr.parse::<SocketAddr>().ok(),
+            _ => None,
+        };
+        // Some peers do not fill the receiver, which tells nothing about us.
+        let addr = match addr.filter(|addr| !addr.ip().is_unspecified()) {
+            Some(addr) => addr,
+            None => return,
+        };
+        self.peers += 1;
+        match self
+            .ips
+            .iter_mut()
+            .find(|observed| observed.ip == addr.ip())
+        {
+            Some(observed) => {
+                observed.peers += 1;
+                if let Err(pos) = observed.ports.binary_search(&addr.port()) {
+                    observed.ports.insert(pos, addr.port());
+                }
+            }
+            None => self.ips.push(ObservedIp {
+                ip: addr.ip(),
+                peers: 1,
+                ports: vec![addr.port()],
+            }),
+        }
+        self.ips
+            .sort_by(|a, b| b.peers.cmp(&a.peers).then_with(|| a.ip.cmp(&b.ip)));
+    }
+
+    /// The IP seen by most of the peers, if any reported one.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ips.first().map(|observed| observed.ip)
+    }
+
+    /// The share of the peers that saw the most seen IP.
+    pub fn agreement(&self) -> f64 {
+        match self.ips.first() {
+            Some(observed) => observed.peers as f64 / self.peers as f64,
+            None => 0.0,
+        }
+    }
+}
+
+impl Display for ExternalAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ip = match self.ip() {
+            Some(ip) => ip,
+            None => {
+                return write!(
+                    f,
+                    "{}  no peer reported our external address",
+                    EMOJI_WARNING
+                )
+            }
+        };
+        let status = if self.ips.len() == 1 {
+            EMOJI_SUCCESS
+        } else {
+            EMOJI_WARNING
+        };
+        write!(
+            f,
+            "{} external address {} seen by {} of {} peers ({:.1}%)",
+            status,
+            ip,
+            self.ips[0].peers,
+            self.peers,
+            self.agreement() * 100.0
+        )?;
+        for observed in self.ips.iter() {
+            let ports: Vec<String> = observed.ports.iter().map(u16::to_string).collect();
+            write!(
+                f,
+                "\n    {} seen by {} {} on {} {}",
+                observed.ip,
+                observed.peers,
+                if observed.peers == 1 { "peer" } else { "peers" },
+                if ports.len() == 1 { "port" } else { "ports" },
+                ports.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for ExternalAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ExternalAddr", 4)?;
+        state.serialize_field("peers", &self.peers)?;
+        state.serialize_field("ip", &self.ip())?;
+        state.serialize_field("agreement", &self.agreement())?;
+        state.serialize_field("ips", &self.ips)?;
+        state.end()
+    }
+}
+
+/// The chain tips of the peers, taken from the start height of their version
+/// messages, which points to the nodes lagging behind the median tip.
+pub struct TipLag {
+    max_lag: u32,
+    tips: Vec<Tip>,
+}
+
+/// The chain tip advertised by a peer.
+#[derive(Serialize)]
+struct Tip {
+    id: String,
+    start_height: i64,
+}
+
+impl TipLag {
+    /// Builds the report, that lists the peers lagging by more than the provided
+    /// blocks behind the median tip.
+    pub fn new(max_lag: u32) -> TipLag {
+        TipLag {
+            max_lag,
+            tips: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, result: &HandshakeResult) {
+        if let Some(Value::Int(start_height)) =
+            peer_version(result).and_then(|ev| ev.pair("start-height"))
+        {
+            self.tips.push(Tip {
+                id: result.id().to_string(),
+                start_height: *start_height,
+            });
+        }
+    }
+
+    /// The median of the peers tips, which is the mean of the middle ones when
+    /// their number is even.
+    pub fn median_height(&self) -> Option<i64> {
+        let mut heights: Vec<i64> = self.tips.iter().map(|tip| tip.start_height).collect();
+        heights.sort_unstable();
+        let middle = heights.len() / 2;
+        match heights.len() {
+            0 => None,
+            len if len % 2 == 1 => Some(heights[middle]),
+            _ => Some((heights[middle - 1] + heights[middle]).div_euclid(2)),
+        }
+    }
+
+    /// The peers lagging behind the median tip by more than the maximum lag, along
+    /// their lag, from the most lagging one.
+    fn lagging(&self) -> Vec<(&Tip, i64)> {
+        let median = match self.median_height() {
+            Some(median) => median,
+            None => return Vec::new(),
+        };
+        let mut lagging: Vec<(&Tip, i64)> = self
+            .tips
+            .iter()
+            .map(|tip| (tip, median - tip.start_height))
+            .filter(|(_, lag)| *lag > i64::from(self.max_lag))
+            .collect();
+        lagging.sort_by(|(a, a_lag), (b, b_lag)| b_lag.cmp(a_lag).then_with(|| a.id.cmp(&b.id)));
+        lagging
+    }
+}
+
+impl Display for TipLag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let median = match self.median_height() {
+            Some(median) => median,
+            None => return write!(f, "{}  no peer reported its chain tip", EMOJI_WARNING),
+        };
+        let lagging = self.lagging();
+        write!(
+            f,
+            "{} median chain tip {} of {} peers, {} lagging more than {} blocks",
+            if lagging.is_empty() {
+                EMOJI_SUCCESS
+            } else {
+                EMOJI_WARNING
+            },
+            median,
+            self.tips.len(),
+            lagging.len(),
+            self.max_lag
+        )?;
+        for (tip, lag) in lagging {
+            write!(
+                f,
+                "\n    {} at {} ({} blocks behind)",
+                tip.id, tip.start_height, lag
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for TipLag {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        /// A lagging peer tip along its lag.
+        #[derive(Serialize)]
+        struct Lagging<'a> {
+            #[serde(flatten)]
+            tip: &'a Tip,
+            lag: i64,
+        }
+
+        let lagging: Vec<Lagging> = self
+            .lagging()
+            .into_iter()
+            .map(|(tip, lag)| Lagging { tip, lag })
+            .collect();
+        let mut state = serializer.serialize_struct("TipLag", 4)?;
+        state.serialize_field("peers", &self.tips.len())?;
+        state.serialize_field("median_height", &self.median_height())?;
+        state.serialize_field("max_lag", &self.max_lag)?;
+        state.serialize_field("lagging", &lagging)?;
+        state.end()
+    }
+}
+
+/// The version message received from the peer, if any.
+fn peer_version(result: &HandshakeResult) -> Option<&Event> {
+    result
+        .event_chain()
+        .events()
+        .iter()
+        .find(|ev| ev.name() == "version" && matches!(ev.direction(), EventDirection::IN))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::view::EventChain;
+
+    use super::*;
+
+    fn result(id: &str, key: &str, val: impl Into<Value>) -> HandshakeResult {
+        let mut event_chain = EventChain::new(id.to_string());
+        event_chain.add(Event::new("version".to_string(), EventDirection::OUT));
+        let mut event = Event::new("version".to_string(), EventDirection::IN);
+        event.set_pair(key.to_string(), val);
+        event_chain.add(event);
+        HandshakeResult::new(event_chain, None)
+    }
+
+    #[test]
+    fn external_addr_counts_the_ips_seen_by_peers() {
+        let mut external_addr = ExternalAddr::default();
+        for receiver in [
+            "198.51.100.2:40000",
+            "203.0.113.7:51236",
+            "203.0.113.7:51234",
+            "203.0.113.7:51234",
+            "0.0.0.0:0",
+        ] {
+            external_addr.add(&result("node", "receiver", receiver));
+        }
+        external_addr.add(&HandshakeResult::new(
+            EventChain::new("node".to_string()),
+            None,
+        ));
+
+        assert_eq!(Some("203.0.113.7".parse().unwrap()), external_addr.ip());
+        assert_eq!(0.75, external_addr.agreement());
+        assert_eq!(
+            format!(
+                "{} external address 203.0.113.7 seen by 3 of 4 peers (75.0%)\
+                \n    203.0.113.7 seen by 3 peers on ports 51234, 51236\
+                \n    198.51.100.2 seen by 1 peer on port 40000",
+                EMOJI_WARNING
+            ),
+            external_addr.to_string()
+        );
+        assert_eq!(
+            r#"{"peers":4,"ip":"203.0.113.7","agreement":0.75,"ips":[{"ip":"203.0.113.7","peers":3,"ports":[51234,51236]},{"ip":"198.51.100.2","peers":1,"ports":[40000]}]}"#,
+            serde_json::to_string(&external_addr).unwrap()
+        );
+    }
+
+    #[test]
+    fn external_addr_shows_when_peers_agree_or_report_nothing() {
+        let mut external_addr = ExternalAddr::default();
+        assert_eq!(
+            format!("{}  no peer reported our external address", EMOJI_WARNING),
+            external_addr.to_string()
+        );
+
+        external_addr.add(&result("node", "receiver", "[2001:db8::7]:8333"));
+        assert_eq!(
+            format!(
+                "{} external address 2001:db8::7 seen by 1 of 1 peers (100.0%)\
+                \n    2001:db8::7 seen by 1 peer on port 8333",
+                EMOJI_SUCCESS
+            ),
+            external_addr.to_string()
+        );
+    }
+
+    #[test]
+    fn tip_lag_lists_the_peers_behind_the_median_tip() {
+        let mut tip_lag = TipLag::new(6);
+        for (id, start_height) in [
+            ("192.168.1.10:8333", 817000),
+            ("192.168.1.11:8333", 817001),
+            ("192.168.1.12:8333", 816990),
+            ("192.168.1.13:8333", 816994),
+            ("192.168.1.14:8333", 0),
+        ] {
+            tip_lag.add(&result(id, "start-height", start_height));
+        }
+        tip_lag.add(&HandshakeResult::new(
+            EventChain::new("192.168.1.15:8333".to_string()),
+            None,
+        ));
+
+        assert_eq!(Some(816994), tip_lag.median_height());
+        assert_eq!(
+            format!(
+                "{} median chain tip 816994 of 5 peers, 1 lagging more than 6 blocks\
+                \n    192.168.1.14:8333 at 0 (816994 blocks behind)",
+                EMOJI_WARNING
+            ),
+            tip_lag.to_string()
+        );
+        assert_eq!(
+            r#"{"peers":5,"median_height":816994,"max_lag":6,"lagging":[{"id":"192.168.1.14:8333","start_height":0,"lag":816994}]}"#,
+            serde_json::to_string(&tip_lag).unwrap()
+        );
+
+        tip_lag.add(&result("192.168.1.16:8333", "start-height", 817002));
+        assert_eq!(Some(816997), tip_lag.median_height());
+        assert_eq!(
+            vec![("192.168.1.14:8333", 816997), ("192.168.1.12:8333", 7)],
+            tip_lag
+                .lagging()
+                .iter()
+                .map(|(tip, lag)| (tip.id.as_str(), *lag))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn tip_lag_shows_when_no_peer_lags_or_reports_its_tip() {
+        let mut tip_lag = TipLag::new(6);
+        assert_eq!(
+            format!("{}  no peer reported its chain tip", EMOJI_WARNING),
+            tip_lag.to_string()
+        );
+
+        tip_lag.add(&result("node", "start-height", 817000));
+        assert_eq!(
+            format!(
+                "{} median chain tip 817000 of 1 peers, 0 lagging more than 6 blocks",
+                EMOJI_SUCCESS
+            ),
+            tip_lag.to_string()
+        );
+    }
+
+    #[test]
+    fn summary_is_empty_without_reports_requested() {
+        let commands = Commands::Bittorrent {
+            info_hash: "0".repeat(40),
+            nodes_addrs: Vec::new(),
+        };
+        assert!(Summary::new(&commands).is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Read},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use super::{protocol::parse_node_addr, P2PError};
+
+/// The argument that makes targets to be read from the standard input.
+pub const STDIN_TARGETS: &str = "-";
+
+/// A node to handshake with, along with the options that override the command
+/// ones just for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub addr: String,
+    pub options: Vec<(String, String)>,
+}
+
+impl Target {
+    pub fn new(addr: String) -> Target {
+        Target {
+            addr,
+            options: Vec::new(),
+        }
+    }
+}
+
+/// Gathers the targets from the command line arguments and the targets file, if any.
+/// An argument equal to [STDIN_TARGETS] makes them to be also read from the standard input.
+/// Repeated targets are discarded, keeping the first occurrence. See [dedup_key] for
+/// how they are compared.
+pub fn load(args: &[String], targets_file: Option<&Path>) -> Result<Vec<Target>, P2PError> {
+    let mut targets = Vec::new();
+    for arg in args {
+        if arg == STDIN_TARGETS {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            targets.extend(parse(&input)?);
+        } else {
+            targets.push(Target::new(arg.to_owned()));
+        }
+    }
+    if let Some(path) = targets_file {
+        let input = fs::read_to_string(path).map_err(|err| P2PError::InvalidTarget {
+            target: path.display().to_string(),
+            reason: format!("cannot read the targets file: {}", err),
+        })?;
+        targets.extend(parse(&input)?);
+    }
+    Ok(dedup(targets))
+}
+
+/// Parses a list of targets, one per line. Each line contains an address, optionally
+/// followed by `key=value` option overrides separated by whitespace. Blank lines and
+/// everything after a `#` are ignored.
+///
+/// ```text
+/// # Our nodes
+/// 192.168.1.10:8333
+/// seed.bitcoin.sipa.be network=bitcoin user_agent=/Satoshi:24.0.1/
+/// ```
+pub fn parse(input: &str) -> Result<Vec<Target>, P2PError> {
+    let mut targets = Vec::new();
+    for (n, line) in input.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let addr = match fields.next() {
+            Some(addr) => addr,
+            None => continue,
+        };
+        let mut target = Target::new(addr.to_string());
+        for field in fields {
+            let (key, val) = field
+                .split_once('=')
+                .filter(|(key, _)| !key.is_empty())
+                .ok_or_else(|| P2PError::InvalidTarget {
+                    target: addr.to_string(),
+                    reason: format!("line {}: option {} is not like key=value", n + 1, field),
+                })?;
+            target.options.push((key.to_string(), val.to_string()));
+        }
+        targets.push(target);
+    }
+    Ok(targets)
+}
+
+fn dedup(targets: Vec<Target>) -> Vec<Target> {
+    let mut seen = HashSet::new();
+    targets
+        .into_iter()
+        .filter(|target| seen.insert(dedup_key(target)))
+        .collect()
+}
+
+/// The node address along the options of the target, so the same node is handshaked
+/// once per set of overrides. Addresses are normalized when they can be parsed, so
+/// hostnames are compared case insensitively and IPv4-mapped IPv6 literals as their
+/// IPv4 ones. Addresses without a port are not the same as the ones with the default
+/// port, as it depends on the protocol and the options, while the ones that cannot be
+/// parsed, like the identities of some protocols, are compared as written.
+fn dedup_key(target: &Target) -> (String, Vec<(String, String)>) {
+    // A port cannot be 0, so it tells the addresses without one apart.
+    let addr = match parse_node_addr(&target.addr, 0) {
+        Ok((host, port)) => match (host.parse::<IpAddr>(), port) {
+            (Ok(ip), 0) => ip.to_canonical().to_string(),
+            (Ok(ip), port) => SocketAddr::new(ip.to_canonical(), port).to_string(),
+            (Err(_), 0) => host.to_lowercase(),
+            (Err(_), port) => format!("{}:{}", host.to_lowercase(), port),
+        },
+        Err(_) => target.addr.clone(),
+    };
+    (addr, target.options.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let input = "
+            # Our nodes
+            192.168.1.10:8333
+
+            192.168.1.11:8333 # the backup one
+        ";
+
+        assert_eq!(
+            vec![
+                Target::new("192.168.1.10:8333".to_string()),
+                Target::new("192.168.1.11:8333".to_string())
+            ],
+            parse(input).unwrap()
+        )
+    }
+
+    #[test]
+    fn parse_reads_option_overrides() {
+        let input = "seed.bitcoin.sipa.be network=testnet user_agent=/Satoshi:24.0.1/";
+
+        assert_eq!(
+            vec![Target {
+                addr: "seed.bitcoin.sipa.be".to_string(),
+                options: vec![
+                    ("network".to_string(), "testnet".to_string()),
+                    ("user_agent".to_string(), "/Satoshi:24.0.1/".to_string())
+                ]
+            }],
+            parse(input).unwrap()
+        )
+    }
+
+    #[test]
+    fn parse_rejects_malformed_options() {
+        for input in ["192.168.1.10:8333 testnet", "192.168.1.10:8333 =testnet"] {
+            assert!(matches!(parse(input), Err(P2PError::InvalidTarget { .. })));
+        }
+    }
+
+    #[test]
+    fn load_deduplicates_addresses_keeping_the_first_one() {
+        let args = [
+            "192.168.1.10:8333".to_string(),
+            "192.168.1.11:8333".to_string(),
+            "192.168.1.10:8333".to_string(),
+        ];
+
+        assert_eq!(
+            vec![
+                Target::new("192.168.1.10:8333".to_string()),
+                Target::new("192.168.1.11:8333".to_string())
+            ],
+            load(&args, None).unwrap()
+        )
+    }
+
+    #[test]
+    fn dedup_compares_normalized_addresses_along_their_options() {
+        let targets = parse(
+            "
+            192.168.1.10:8333
+            [::ffff:192.168.1.10]:8333
+            192.168.1.10
+            192.168.1.10 network=testnet
+            192.168.1.10 network=testnet
+            Seed.Bitcoin.Sipa.Be:8333
+            seed.bitcoin.sipa.be:8333
+            02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619@127.0.0.1:9735
+            02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619@127.0.0.1:9735
+            ",
+        )
+        .unwrap();
+
+        let targets = dedup(targets);
+        let addrs: Vec<(&str, usize)> = targets
+            .iter()
+            .map(|target| (target.addr.as_str(), target.options.len()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("192.168.1.10:8333", 0),
+                ("192.168.1.10", 0),
+                ("192.168.1.10", 1),
+                ("Seed.Bitcoin.Sipa.Be:8333", 0),
+                ("02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619@127.0.0.1:9735", 0),
+            ],
+            addrs
+        );
+    }
+}
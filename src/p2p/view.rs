@@ -1,10 +1,16 @@
 use std::{
     fmt::{self, Display},
+    net::SocketAddr,
     ops::Add,
     time::{Duration, Instant},
 };
 
-use super::P2PError;
+use serde::{
+    ser::{SerializeMap, SerializeStruct},
+    Serialize, Serializer,
+};
+
+use super::{P2PError, Phase};
 
 pub const EMOJI_SUCCESS: &str = "\u{2705}";
 pub const EMOJI_WARNING: &str = "\u{26A0}\u{FE0F}";
@@ -12,47 +18,94 @@ pub const EMOJI_FAILURE: &str = "\u{274C}";
 pub const EMOJI_TIMEOUT: &str = "\u{274C} \u{1F550}";
 pub const EMOJI_DIRECTION_OUT: &str = "\u{1F6EB}";
 pub const EMOJI_DIRECTION_IN: &str = "\u{1F6EC}";
+pub const EMOJI_DIRECTION_INTERNAL: &str = "\u{2699}\u{FE0F}";
 
 pub struct HandshakeResult {
-    id: String,
-    result: Result<EventChain, P2PError>,
+    event_chain: EventChain,
+    error: Option<P2PError>,
 }
 
 impl HandshakeResult {
-    pub fn new(id: String, result: Result<EventChain, P2PError>) -> HandshakeResult {
-        HandshakeResult { id, result }
+    pub fn new(event_chain: EventChain, error: Option<P2PError>) -> HandshakeResult {
+        HandshakeResult { event_chain, error }
     }
 
     pub fn id(&self) -> &str {
-        self.id.as_ref()
+        self.event_chain.id()
+    }
+
+    /// The events collected during the handshake. They are kept even if the
+    /// handshake failed, so they show the point at which it happened.
+    pub fn event_chain(&self) -> &EventChain {
+        &self.event_chain
+    }
+
+    pub fn error(&self) -> Option<&P2PError> {
+        self.error.as_ref()
     }
 
     pub fn result(&self) -> Result<&EventChain, &P2PError> {
-        self.result.as_ref()
+        match &self.error {
+            Some(err) => Err(err),
+            None => Ok(&self.event_chain),
+        }
     }
 }
 
 impl Display for HandshakeResult {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.result.is_ok() {
-            true => {
-                write!(f, "{}", self.result().unwrap())
-            }
-            false => {
-                write!(
-                    f,
-                    "{} {}: {}",
-                    EMOJI_FAILURE,
-                    self.id,
-                    self.result().err().unwrap()
-                )
-            }
+        let err = match &self.error {
+            None => return write!(f, "{}", self.event_chain),
+            Some(err) => err,
+        };
+        let status = match err {
+            P2PError::Timeout(_) => EMOJI_TIMEOUT,
+            _ => EMOJI_FAILURE,
+        };
+        if self.event_chain.is_empty() {
+            return write!(f, "{} {}: {}", status, self.id(), err);
         }
+        self.event_chain.fmt_timeline(f, status)?;
+        write!(f, " {}", err)
+    }
+}
+
+impl Serialize for HandshakeResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("HandshakeResult", 6)?;
+        state.serialize_field("id", self.id())?;
+        state.serialize_field("peer_addr", &self.event_chain.peer_addr())?;
+        state.serialize_field("complete", &self.event_chain.is_complete())?;
+        state.serialize_field("total_time_us", &self.event_chain.total_time().as_micros())?;
+        state.serialize_field("events", &self.event_chain.timed_events())?;
+        state.serialize_field("error", &self.error)?;
+        state.end()
+    }
+}
+
+impl Serialize for P2PError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let phase = match self {
+            P2PError::Timeout(phase) => Some(phase),
+            _ => None,
+        };
+        let mut state = serializer.serialize_struct("P2PError", 3)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("phase", &phase)?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+impl Serialize for Phase {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
     }
 }
 
 pub struct EventChain {
     id: String,
+    peer_addr: Option<SocketAddr>,
     complete: bool,
     events: Vec<Event>,
 }
@@ -61,6 +114,7 @@ impl EventChain {
     pub fn new(id: String) -> Self {
         EventChain {
             id,
+            peer_addr: None,
             events: Vec::new(),
             complete: false,
         }
@@ -82,6 +136,14 @@ impl EventChain {
         self.events.get(n)
     }
 
+    pub fn events(&self) -> &[Event] {
+        self.events.as_ref()
+    }
+
+    pub fn into_events(self) -> Vec<Event> {
+        self.events
+    }
+
     pub fn mark_as_complete(&mut self) {
         self.complete = true;
     }
@@ -93,16 +155,44 @@ impl EventChain {
     pub fn id(&self) -> &str {
         self.id.as_ref()
     }
+
+    pub fn set_peer_addr(&mut self, peer_addr: SocketAddr) {
+        self.peer_addr = Some(peer_addr);
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
 }
 
-impl Display for EventChain {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let status = if self.is_complete() {
-            EMOJI_SUCCESS
-        } else {
-            EMOJI_TIMEOUT
-        };
+impl EventChain {
+    /// Time elapsed between the first and the last event.
+    pub fn total_time(&self) -> Duration {
+        match (self.events.first(), self.events.last()) {
+            (Some(first), Some(last)) => last.time().duration_since(first.time()),
+            _ => Duration::from_millis(0),
+        }
+    }
+
+    fn timed_events(&self) -> Vec<TimedEvent<'_>> {
+        let start = self.events.first().map(Event::time);
+        self.events
+            .iter()
+            .map(|event| TimedEvent {
+                event,
+                offset: start.map_or(Duration::from_millis(0), |start| {
+                    event.time().duration_since(start)
+                }),
+            })
+            .collect()
+    }
+
+    fn fmt_timeline(&self, f: &mut fmt::Formatter<'_>, status: &str) -> fmt::Result {
         write!(f, "{} - {}", status, self.id())?;
+        // Show the resolved address only if it adds information to the id.
+        if let Some(peer_addr) = self.peer_addr.filter(|addr| addr.to_string() != self.id) {
+            write!(f, " ({})", peer_addr)?;
+        }
         write!(f, " || ")?;
 
         let mut last_ev: Option<&Event> = None;
@@ -123,11 +213,64 @@ impl Display for EventChain {
     }
 }
 
+impl Display for EventChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.is_complete() {
+            EMOJI_SUCCESS
+        } else {
+            EMOJI_TIMEOUT
+        };
+        self.fmt_timeline(f, status)
+    }
+}
+
+impl Serialize for EventChain {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("EventChain", 5)?;
+        state.serialize_field("id", self.id())?;
+        state.serialize_field("peer_addr", &self.peer_addr)?;
+        state.serialize_field("complete", &self.complete)?;
+        state.serialize_field("total_time_us", &self.total_time().as_micros())?;
+        state.serialize_field("events", &self.timed_events())?;
+        state.end()
+    }
+}
+
+/// An event along its offset from the start of the event chain it belongs to.
+struct TimedEvent<'a> {
+    event: &'a Event,
+    offset: Duration,
+}
+
+impl Serialize for TimedEvent<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Event", 4)?;
+        state.serialize_field("name", self.event.name())?;
+        state.serialize_field("direction", self.event.direction())?;
+        state.serialize_field("offset_us", &self.offset.as_micros())?;
+        state.serialize_field("data", &DataPairs(self.event.data_pairs()))?;
+        state.end()
+    }
+}
+
+/// Serializes data pairs as a map, keeping their insertion order.
+struct DataPairs<'a>(&'a [(String, Value)]);
+
+impl Serialize for DataPairs<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (k, v) in self.0 {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
 pub struct Event {
     name: String,
     time: Instant,
     direction: EventDirection,
-    data_pairs: Vec<(String, String)>,
+    data_pairs: Vec<(String, Value)>,
 }
 
 impl Event {
@@ -152,12 +295,103 @@ impl Event {
         &self.direction
     }
 
-    pub fn data_pairs(&self) -> &[(String, String)] {
+    pub fn data_pairs(&self) -> &[(String, Value)] {
         self.data_pairs.as_ref()
     }
 
-    pub fn set_pair(&mut self, key: String, val: String) {
-        self.data_pairs.push((key, val));
+    /// The value of the first data pair with the provided key, if any.
+    pub fn pair(&self, key: &str) -> Option<&Value> {
+        self.data_pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, val)| val)
+    }
+
+    pub fn set_pair(&mut self, key: String, val: impl Into<Value>) {
+        self.data_pairs.push((key, val.into()));
+    }
+}
+
+/// The value of an event data pair. They are shown as text in the time lines, while
+/// the machine readable outputs keep their type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Text(String),
+    Int(i64),
+    UInt(u64),
+    Bool(bool),
+    List(Vec<String>),
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Text(text) => write!(f, "{}", text),
+            Value::Int(int) => write!(f, "{}", int),
+            Value::UInt(uint) => write!(f, "{}", uint),
+            Value::Bool(bool) => write!(f, "{}", bool),
+            Value::List(items) => write!(f, "{}", items.join(",")),
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Text(text) => serializer.serialize_str(text),
+            Value::Int(int) => serializer.serialize_i64(*int),
+            Value::UInt(uint) => serializer.serialize_u64(*uint),
+            Value::Bool(bool) => serializer.serialize_bool(*bool),
+            Value::List(items) => items.serialize(serializer),
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Text(text)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(int: i64) -> Self {
+        Value::Int(int)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(int: i32) -> Self {
+        Value::Int(int.into())
+    }
+}
+
+impl From<u64> for Value {
+    fn from(uint: u64) -> Self {
+        Value::UInt(uint)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(uint: u32) -> Self {
+        Value::UInt(uint.into())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(bool: bool) -> Self {
+        Value::Bool(bool)
+    }
+}
+
+impl From<Vec<String>> for Value {
+    fn from(items: Vec<String>) -> Self {
+        Value::List(items)
     }
 }
 
@@ -181,6 +415,19 @@ impl Display for Event {
 pub enum EventDirection {
     IN,
     OUT,
+    /// Events that are not messages, like the ones of the connection lifecycle.
+    INTERNAL,
+}
+
+impl Serialize for EventDirection {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let direction = match self {
+            EventDirection::IN => "in",
+            EventDirection::OUT => "out",
+            EventDirection::INTERNAL => "internal",
+        };
+        serializer.serialize_str(direction)
+    }
 }
 
 impl Display for EventDirection {
@@ -188,6 +435,7 @@ impl Display for EventDirection {
         let direction = match self {
             EventDirection::IN => EMOJI_DIRECTION_IN,
             EventDirection::OUT => EMOJI_DIRECTION_OUT,
+            EventDirection::INTERNAL => EMOJI_DIRECTION_INTERNAL,
         };
         write!(f, "{}", direction)
     }
@@ -209,6 +457,44 @@ mod tests {
         )
     }
 
+    #[test]
+    fn typed_values_display_as_text_and_serialize_with_their_type() {
+        let mut event = Event::new("version".to_string(), EventDirection::IN);
+        event.set_pair("vers".to_string(), 70016u32);
+        event.set_pair("start-height".to_string(), -1);
+        event.set_pair("relay".to_string(), true);
+        event.set_pair(
+            "services".to_string(),
+            vec!["NETWORK".to_string(), "WITNESS".to_string()],
+        );
+
+        assert_eq!(
+            format!(
+                "version {} (vers:70016 start-height:-1 relay:true services:NETWORK,WITNESS)",
+                EMOJI_DIRECTION_IN
+            ),
+            event.to_string()
+        );
+        assert_eq!(
+            r#"{"vers":70016,"start-height":-1,"relay":true,"services":["NETWORK","WITNESS"]}"#,
+            serde_json::to_string(&DataPairs(event.data_pairs())).unwrap()
+        );
+    }
+
+    #[test]
+    fn internal_event_displays_correctly() {
+        let mut event = Event::new("connect".to_string(), EventDirection::INTERNAL);
+        event.set_pair("addr".to_string(), "192.168.1.1:8333".to_string());
+
+        assert_eq!(
+            format!(
+                "connect {} (addr:192.168.1.1:8333)",
+                EMOJI_DIRECTION_INTERNAL
+            ),
+            event.to_string()
+        )
+    }
+
     #[test]
     fn event_chain_shows_nice_user_output_on_success() {
         let mut chain = EventChain::new("192.168.1.1:8333".to_string());
@@ -259,6 +545,21 @@ mod tests {
         )
     }
 
+    #[test]
+    fn event_chain_shows_resolved_peer_address() {
+        let mut chain = EventChain::new("seed.bitcoin.sipa.be:8333".to_string());
+        chain.set_peer_addr("192.168.1.1:8333".parse().unwrap());
+        chain.add(Event::new("version".to_string(), EventDirection::OUT));
+
+        assert_eq!(
+            format!(
+                "{} - seed.bitcoin.sipa.be:8333 (192.168.1.1:8333) || version {} || total time 0ns.",
+                EMOJI_TIMEOUT, EMOJI_DIRECTION_OUT
+            ),
+            chain.to_string()
+        )
+    }
+
     #[test]
     fn incomplete_event_chain_shows_nice_user_output() {
         let mut chain = EventChain::new("192.168.1.1:8333".to_string());
@@ -298,12 +599,7 @@ mod tests {
         event_chain.add(Event::new("version".to_string(), EventDirection::IN));
         event_chain.mark_as_complete();
 
-        let result: Result<EventChain, P2PError> = Result::Ok(event_chain);
-
-        let hr = HandshakeResult {
-            id: id.clone(),
-            result,
-        };
+        let hr = HandshakeResult::new(event_chain, None);
 
         assert_eq!(
             format!(
@@ -318,23 +614,111 @@ mod tests {
     fn handshake_result_displays_error_on_failure() {
         let id = "192.168.1.1:8333".to_string();
 
-        let error = P2PError {
-            message: "connection refused !".to_string(),
+        let error = P2PError::ConnectFailed {
+            addr: id.parse().unwrap(),
+            source: std::io::ErrorKind::ConnectionRefused.into(),
         };
 
-        let result: Result<EventChain, P2PError> = Result::Err(error);
-
-        let hr = HandshakeResult {
-            id: id.clone(),
-            result,
-        };
+        let hr = HandshakeResult::new(EventChain::new(id), Some(error));
 
         assert_eq!(
             format!(
-                "{} 192.168.1.1:8333: P2P error: connection refused !",
+                "{} 192.168.1.1:8333: P2P error: cannot connect to 192.168.1.1:8333: connection refused",
                 EMOJI_FAILURE
             ),
             hr.to_string()
         )
     }
+
+    #[test]
+    fn handshake_result_displays_partial_event_chain_on_failure() {
+        let mut event_chain = EventChain::new("192.168.1.1:8333".to_string());
+
+        let fixed_time = Instant::now();
+
+        event_chain.add(Event {
+            name: "version".to_string(),
+            direction: EventDirection::OUT,
+            time: fixed_time,
+            data_pairs: Vec::new(),
+        });
+
+        event_chain.add(Event {
+            name: "version".to_string(),
+            direction: EventDirection::IN,
+            time: fixed_time.add(Duration::from_millis(100)),
+            data_pairs: Vec::new(),
+        });
+
+        let hr = HandshakeResult::new(event_chain, Some(P2PError::PeerClosed(None)));
+
+        assert_eq!(
+            format!(
+                "{} - 192.168.1.1:8333 || version {} -- 100ms --> version {} || total time 100ms. P2P error: connection closed by peer",
+                EMOJI_FAILURE, EMOJI_DIRECTION_OUT, EMOJI_DIRECTION_IN
+            ),
+            hr.to_string()
+        )
+    }
+
+    #[test]
+    fn handshake_result_displays_timeouts() {
+        let mut event_chain = EventChain::new("192.168.1.1:8333".to_string());
+        event_chain.add(Event::new("version".to_string(), EventDirection::OUT));
+
+        let hr = HandshakeResult::new(event_chain, Some(P2PError::Timeout(Phase::Handshake)));
+
+        assert_eq!(
+            format!(
+                "{} - 192.168.1.1:8333 || version {} || total time 0ns. P2P error: timed out during handshake",
+                EMOJI_TIMEOUT, EMOJI_DIRECTION_OUT
+            ),
+            hr.to_string()
+        )
+    }
+
+    #[test]
+    fn handshake_result_serializes_to_json() {
+        let mut event_chain = EventChain::new("seed.bitcoin.sipa.be:8333".to_string());
+        event_chain.set_peer_addr("192.168.1.1:8333".parse().unwrap());
+
+        let fixed_time = Instant::now();
+
+        let mut event = Event {
+            name: "version".to_string(),
+            direction: EventDirection::OUT,
+            time: fixed_time,
+            data_pairs: Vec::new(),
+        };
+        event.set_pair("k2".to_string(), "v2".to_string());
+        event.set_pair("k1".to_string(), "v1".to_string());
+        event_chain.add(event);
+
+        event_chain.add(Event {
+            name: "version".to_string(),
+            direction: EventDirection::IN,
+            time: fixed_time.add(Duration::from_micros(1500)),
+            data_pairs: Vec::new(),
+        });
+
+        let hr = HandshakeResult::new(event_chain, Some(P2PError::Timeout(Phase::Handshake)));
+
+        assert_eq!(
+            r#"{"id":"seed.bitcoin.sipa.be:8333","peer_addr":"192.168.1.1:8333","complete":false,"total_time_us":1500,"events":[{"name":"version","direction":"out","offset_us":0,"data":{"k2":"v2","k1":"v1"}},{"name":"version","direction":"in","offset_us":1500,"data":{}}],"error":{"kind":"timeout","phase":"handshake","message":"P2P error: timed out during handshake"}}"#,
+            serde_json::to_string(&hr).unwrap()
+        )
+    }
+
+    #[test]
+    fn successful_handshake_result_serializes_null_error() {
+        let mut event_chain = EventChain::new("192.168.1.1:8333".to_string());
+        event_chain.add(Event::new("verack".to_string(), EventDirection::IN));
+        event_chain.mark_as_complete();
+
+        let json = serde_json::to_value(HandshakeResult::new(event_chain, None)).unwrap();
+
+        assert_eq!(true, json["complete"]);
+        assert!(json["error"].is_null());
+        assert!(json["peer_addr"].is_null());
+    }
 }
@@ -1,9 +1,19 @@
-use std::env;
+use std::{env, num::NonZeroUsize};
 
+use bitcoin::Network;
+use bytes::BytesMut;
+use futures::StreamExt;
 use p2p_handshake::p2p::{
-    config::{Commands, HandshakeConfig},
-    handshake,
-    view::{EventDirection, HandshakeResult},
+    config::{Chain, Commands, HandshakeConfig, OutputFormat, RunConfig, VersionConfig},
+    handshake, handshake_with,
+    protocol::{Protocol, Session},
+    targets::Target,
+    view::{Event, EventDirection, HandshakeResult},
+    P2PError,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
 };
 
 #[tokio::test]
@@ -15,44 +25,152 @@ async fn it_makes_btc_handshake() {
         .collect();
 
     let config = HandshakeConfig {
-        timeout: 500,
+        run: run_config(),
+        output: OutputFormat::Text,
+        targets_file: None,
         commands: Commands::Btc {
             nodes_addrs,
             user_agent: "/Satoshi:23.0.0/".to_string(),
+            chain: Chain::Bitcoin,
+            network: Network::Bitcoin,
+            version: VersionConfig::default(),
+            v2: false,
+            max_clock_skew: 600,
+            pings: 0,
+            ping_timeout: 2000,
+            external_addr: false,
+            tip_lag: None,
         },
     };
     handshake(config)
-        .await
         .unwrap()
+        .collect::<Vec<_>>()
+        .await
         .iter()
         .for_each(assert_handshake);
 }
 
+fn run_config() -> RunConfig {
+    RunConfig {
+        connect_timeout: 500,
+        handshake_timeout: 500,
+        deadline: None,
+        concurrency: NonZeroUsize::new(256).unwrap(),
+        rate: None,
+        proxy: None,
+    }
+}
+
+/// A protocol defined outside the crate, that completes once the peer echoes its greeting.
+struct Echo {
+    greeting: &'static [u8],
+    echoed: bool,
+}
+
+impl Protocol for Echo {
+    fn default_port(&self) -> u16 {
+        7
+    }
+
+    fn start(&mut self, session: &mut Session) -> Result<(), P2PError> {
+        let event = Event::new("greeting".to_string(), EventDirection::OUT);
+        session.send(event, self.greeting.to_vec())
+    }
+
+    fn on_data(&mut self, buffer: &mut BytesMut, session: &mut Session) -> Result<(), P2PError> {
+        if buffer.len() < self.greeting.len() {
+            return Ok(());
+        }
+        if buffer.split_to(self.greeting.len()).as_ref() != self.greeting {
+            return Err(P2PError::ProtocolViolation("wrong echo".to_string()));
+        }
+        self.echoed = true;
+        session.publish(Event::new("greeting".to_string(), EventDirection::IN))
+    }
+
+    fn is_complete(&self) -> bool {
+        self.echoed
+    }
+}
+
+#[tokio::test]
+async fn it_makes_handshakes_of_external_protocols() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0; 64];
+                while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                    stream.write_all(&buf[..n]).await.unwrap();
+                }
+            });
+        }
+    });
+    let targets = vec![
+        Target::new(addr.to_string()),
+        Target::new(format!("localhost:{}", addr.port())),
+    ];
+
+    let results = handshake_with(&run_config(), targets, |_| {
+        Ok(Echo {
+            greeting: b"hello",
+            echoed: false,
+        })
+    })
+    .collect::<Vec<_>>()
+    .await;
+
+    assert_eq!(2, results.len());
+    for result in results {
+        let ev_chain = result.result().unwrap();
+        assert!(ev_chain.is_complete());
+        assert_eq!(addr, ev_chain.peer_addr().unwrap());
+    }
+}
+
 fn assert_handshake(result: &HandshakeResult) {
     let ev_chain = result.result().unwrap();
 
-    assert!(ev_chain.is_complete() == true);
-    assert!(ev_chain.len() == 4);
+    assert!(ev_chain.is_complete());
+
+    // The connection lifecycle events go before and after the handshake messages,
+    // while the peer clock is measured once its version is received.
+    let lifecycle: Vec<&str> = ev_chain
+        .events()
+        .iter()
+        .filter(|ev| matches!(ev.direction(), EventDirection::INTERNAL))
+        .map(|ev| ev.name())
+        .collect();
+    assert_eq!(
+        vec!["resolve", "connect", "first-byte", "clock", "closed"],
+        lifecycle
+    );
+
+    // Peers may also announce their preferences, like wtxidrelay or sendaddrv2, before
+    // their verack.
+    let messages: Vec<&Event> = ev_chain
+        .events()
+        .iter()
+        .filter(|ev| !matches!(ev.direction(), EventDirection::INTERNAL))
+        .filter(|ev| ["version", "verack"].contains(&ev.name()))
+        .collect();
+    assert!(messages.len() == 4);
 
-    assert!(ev_chain.get(0).unwrap().name().eq("version"));
-    assert!(matches!(
-        ev_chain.get(0).unwrap().direction(),
-        EventDirection::OUT
-    ));
+    assert!(messages[0].name().eq("version"));
+    assert!(matches!(messages[0].direction(), EventDirection::OUT));
 
-    assert!(ev_chain.get(1).unwrap().name().eq("version"));
-    assert!(matches!(
-        ev_chain.get(1).unwrap().direction(),
-        EventDirection::IN
-    ));
+    assert!(messages[1].name().eq("version"));
+    assert!(matches!(messages[1].direction(), EventDirection::IN));
 
     // Last 2 events should be the "verack" (IN and OUT) and they can happen at any time.
     // In order to make this tests more resilient, we just check types and that their
     // directions are different.
-    assert!(ev_chain.get(2).unwrap().name().eq("verack"));
-    assert!(ev_chain.get(3).unwrap().name().eq("verack"));
+    assert!(messages[2].name().eq("verack"));
+    assert!(messages[3].name().eq("verack"));
 
-    let direction_2 = ev_chain.get(2).unwrap().direction();
-    let direction_3 = ev_chain.get(3).unwrap().direction();
+    let direction_2 = messages[2].direction();
+    let direction_3 = messages[3].direction();
     assert!(direction_2.to_string() != direction_3.to_string());
 }